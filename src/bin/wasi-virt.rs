@@ -6,7 +6,10 @@ use anyhow::{bail, Result};
 use clap::{ArgAction, Parser};
 use semver::Version;
 
-use wasi_virt::{StdioCfg, WasiVirt, DEFAULT_INSERT_WASI_VERSION};
+use wasi_virt::{
+    ConfigSource, ConfigVersion, ExitMode, ExitStatus, FrozenClocks, KeyCase, RandomPolicy,
+    SleepLimitAction, StdioCfg, TerminalCfg, WasiVirt, DEFAULT_INSERT_WASI_VERSION,
+};
 
 #[derive(Parser, Debug)]
 #[command(verbatim_doc_comment, author, version, about, long_about = None)]
@@ -35,10 +38,63 @@ struct Args {
     #[arg(long, default_missing_value="true", num_args=0..=1)]
     allow_clocks: Option<bool>,
 
+    /// Freeze the wall clock at the given time since the Unix epoch
+    #[arg(long, value_name("SECONDS[.NANOS]"), value_parser = parse_instant)]
+    freeze_clocks: Option<(u64, u32)>,
+
+    /// Frozen monotonic clock instant in nanoseconds (defaults to zero)
+    #[arg(long, value_name("NANOS"), requires("freeze_clocks"))]
+    freeze_monotonic: Option<u64>,
+
+    /// Simulate the clocks, advancing them by the given nanoseconds on each monotonic clock
+    /// reading and jumping forward on timers, starting from the `--freeze-clocks` instants
+    #[arg(long, value_name("STEP_NANOS"))]
+    simulate_clocks: Option<u64>,
+
+    /// Offset the host wall clock by the given seconds, which may be negative
+    #[arg(long, value_name("SECONDS"), allow_negative_numbers(true), conflicts_with_all(["freeze_clocks", "simulate_clocks"]))]
+    clocks_offset: Option<i64>,
+
+    /// Run the monotonic clock and its timers the given times faster than the host clock
+    #[arg(long, value_name("FACTOR"), conflicts_with_all(["freeze_clocks", "simulate_clocks"]))]
+    clocks_rate: Option<f64>,
+
+    /// Round the host clocks down to the given granularity in nanoseconds
+    #[arg(long, value_name("NANOS"), conflicts_with_all(["freeze_clocks", "simulate_clocks", "clocks_offset", "clocks_rate"]))]
+    clocks_granularity: Option<u64>,
+
+    /// Randomly jitter the rounding of the clocks to the granularity
+    #[arg(long, requires("clocks_granularity"))]
+    clocks_jitter: bool,
+
+    /// Seed the clocks jitter for reproducible builds, instead of from the OS randomness
+    #[arg(long, value_name("SEED"), requires("clocks_jitter"))]
+    clocks_jitter_seed: Option<u64>,
+
+    /// Limit the duration the component may wait on monotonic clock timers, in nanoseconds
+    #[arg(long, value_name("NANOS"))]
+    max_sleep: Option<u64>,
+
+    /// The action for waits beyond the `--max-sleep` limit
+    #[arg(long, value_enum, value_name("ACTION"), requires("max_sleep"))]
+    max_sleep_action: Option<SleepLimitAction>,
+
     /// Allow the component to exit
     #[arg(long, default_missing_value="true", num_args=0..=1)]
     allow_exit: Option<bool>,
 
+    /// How guest calls to `wasi:cli/exit` are handled, overriding --allow-exit
+    #[arg(long, value_enum, value_name("MODE"))]
+    exit_mode: Option<ExitMode>,
+
+    /// The status reported when the component exits with success
+    #[arg(long, value_enum, value_name("STATUS"))]
+    exit_success_as: Option<ExitStatus>,
+
+    /// The status reported when the component exits with failure
+    #[arg(long, value_enum, value_name("STATUS"))]
+    exit_failure_as: Option<ExitStatus>,
+
     // HTTP
     /// Enable HTTP
     #[arg(long, default_missing_value="true", num_args=0..=1)]
@@ -49,13 +105,43 @@ struct Args {
     #[arg(long, default_missing_value="true", num_args=0..=1)]
     allow_random: Option<bool>,
 
+    /// Generate all random data deterministically from the given seed
+    #[arg(long, value_name("SEED"))]
+    random_seed: Option<u64>,
+
+    /// Generate all random data deterministically from a seed read from the given config
+    /// property at runtime
+    #[arg(long, value_name("KEY"), conflicts_with("random_seed"))]
+    random_seed_config: Option<String>,
+
+    /// Record all random data returned from the host to the given log file path under the host
+    /// preopens, which requires `--allow-fs`
+    #[arg(long, value_name("PATH"), conflicts_with_all(["random_seed", "random_seed_config"]))]
+    random_record: Option<String>,
+
+    /// Replay the random data of a recorded log file, embedding it into the virtualization
+    #[arg(long, value_name("PATH"), conflicts_with_all(["random_seed", "random_seed_config", "random_record"]))]
+    random_replay: Option<PathBuf>,
+
+    /// The policy for the cryptographically-secure `wasi:random/random`, overriding the random mode
+    #[arg(long, value_enum, value_name("POLICY"))]
+    random_secure: Option<RandomPolicy>,
+
+    /// The policy for `wasi:random/insecure`, overriding the random mode
+    #[arg(long, value_enum, value_name("POLICY"))]
+    random_insecure: Option<RandomPolicy>,
+
+    /// The policy for `wasi:random/insecure-seed`, overriding the random mode
+    #[arg(long, value_enum, value_name("POLICY"))]
+    random_insecure_seed: Option<RandomPolicy>,
+
     // SOCKETS
     /// Enable Sockets
     #[arg(long, default_missing_value="true", num_args=0..=1)]
     allow_sockets: Option<bool>,
 
     // ENV
-    /// Allow unrestricted access to host environment variables, or to a comma-separated list of variable names.
+    /// Allow unrestricted access to host environment variables, or to a comma-separated list of variable names or `*` patterns.
     #[arg(long, num_args(0..), use_value_delimiter(true), require_equals(true), value_name("ENV_VAR"), help_heading = "Env")]
     allow_env: Option<Vec<String>>,
 
@@ -63,8 +149,72 @@ struct Args {
     #[arg(short, long, use_value_delimiter(true), value_name("ENV=VAR"), value_parser = parse_key_val::<String, String>, help_heading = "Env")]
     env: Option<Vec<(String, String)>>,
 
+    /// Load environment variable overrides from a `.toml`, `.json` or dotenv file
+    #[arg(long, value_name("PATH"), help_heading = "Env")]
+    env_file: Option<Vec<PathBuf>>,
+
+    /// Resolve `${ENV_VAR}` references to permitted host environment variables in the overrides at runtime
+    #[arg(long, default_missing_value="true", num_args=0..=1, help_heading = "Env")]
+    interpolate_env: Option<bool>,
+
+    /// Hide environment variables from the guest, even when allowed from the host
+    #[arg(
+        long,
+        use_value_delimiter(true),
+        value_name("ENV_VAR"),
+        help_heading = "Env"
+    )]
+    unset_env: Option<Vec<String>>,
+
+    /// Mark environment variables or `*` patterns as secret, redacting their values from the debug output
+    #[arg(
+        long,
+        use_value_delimiter(true),
+        value_name("ENV_VAR"),
+        help_heading = "Env"
+    )]
+    secret_env: Option<Vec<String>>,
+
+    /// Allow overrides of secret environment variables, embedding their values in the virtualization as is
+    #[arg(long, default_missing_value="true", num_args=0..=1, help_heading = "Env")]
+    embed_secret_env: Option<bool>,
+
+    /// Require an environment variable, optionally with a regex its value must match
+    #[arg(long, value_name("ENV_VAR[=REGEX]"), value_parser = parse_required, help_heading = "Env")]
+    require_env: Option<Vec<(String, Option<String>)>>,
+
+    /// Expose a host environment variable under a different name
+    #[arg(long, value_name("ENV=HOST_ENV"), value_parser = parse_key_val::<String, String>, help_heading = "Env")]
+    map_env: Option<Vec<(String, String)>>,
+
+    /// Allow access to the host command-line arguments
+    #[arg(long, default_missing_value="true", num_args=0..=1, help_heading = "Env")]
+    allow_args: Option<bool>,
+
+    /// Insert a fixed argument after the program name, ahead of the host arguments
+    #[arg(
+        long,
+        value_name("ARG"),
+        allow_hyphen_values(true),
+        help_heading = "Env"
+    )]
+    prepend_arg: Option<Vec<String>>,
+
+    /// Add a fixed argument after the host arguments
+    #[arg(
+        long,
+        value_name("ARG"),
+        allow_hyphen_values(true),
+        help_heading = "Env"
+    )]
+    arg: Option<Vec<String>>,
+
+    /// Set the initial working directory within the virtual filesystem
+    #[arg(long, value_name("DIR"), help_heading = "Env")]
+    cwd: Option<String>,
+
     // CONFIG
-    /// Allow unrestricted access to host  configuration properties, or to a comma-separated list of property names.
+    /// Allow unrestricted access to host  configuration properties, or to a comma-separated list of property names or `*` patterns.
     #[arg(long, num_args(0..), use_value_delimiter(true), require_equals(true), value_name("PROPERTY_NAME"), help_heading = "Config (experimental)")]
     allow_config: Option<Vec<String>>,
 
@@ -72,6 +222,67 @@ struct Args {
     #[arg(short, long, use_value_delimiter(true), value_name("NAME=VALUE"), value_parser = parse_key_val::<String, String>, help_heading = "Config (experimental)")]
     config: Option<Vec<(String, String)>>,
 
+    /// Load config property overrides from a `.toml`, `.json` or dotenv file, flattening nested tables to dotted names
+    #[arg(long, value_name("PATH"), help_heading = "Config (experimental)")]
+    config_file: Option<Vec<PathBuf>>,
+
+    /// Scope host config properties to a namespace, stripping the prefix from the names seen by the guest
+    #[arg(long, value_name("PREFIX"), help_heading = "Config (experimental)")]
+    config_prefix: Option<String>,
+
+    /// Where host config properties are read from, where `env` reads them from environment variables
+    #[arg(
+        long,
+        value_enum,
+        value_name("SOURCE"),
+        help_heading = "Config (experimental)"
+    )]
+    config_source: Option<ConfigSource>,
+
+    /// How environment variable names are transformed into config property names with `--config-source=env`
+    #[arg(
+        long,
+        value_enum,
+        value_name("CASE"),
+        help_heading = "Config (experimental)"
+    )]
+    config_key_case: Option<KeyCase>,
+
+    /// The `wasi:config/store` version imported from the host, all supported versions are exported to the guest
+    #[arg(
+        long,
+        value_enum,
+        value_name("VERSION"),
+        help_heading = "Config (experimental)"
+    )]
+    config_host_version: Option<ConfigVersion>,
+
+    /// Hide config properties from the guest, even when allowed from the host
+    #[arg(
+        long,
+        use_value_delimiter(true),
+        value_name("PROPERTY_NAME"),
+        help_heading = "Config (experimental)"
+    )]
+    unset_config: Option<Vec<String>>,
+
+    /// Mark config properties or `*` patterns as secret, redacting their values from the debug output
+    #[arg(
+        long,
+        use_value_delimiter(true),
+        value_name("PROPERTY_NAME"),
+        help_heading = "Config (experimental)"
+    )]
+    secret_config: Option<Vec<String>>,
+
+    /// Allow overrides of secret config properties, embedding their values in the virtualization as is
+    #[arg(long, default_missing_value="true", num_args=0..=1, help_heading = "Config (experimental)")]
+    embed_secret_config: Option<bool>,
+
+    /// Require a config property, optionally with a regex its value must match
+    #[arg(long, value_name("PROPERTY_NAME[=REGEX]"), value_parser = parse_required, help_heading = "Config (experimental)")]
+    require_config: Option<Vec<(String, Option<String>)>>,
+
     // FS
     /// Allow unrestricted access to host preopens
     #[arg(long, default_missing_value="true", num_args=0..=1, help_heading = "Fs")]
@@ -101,6 +312,18 @@ struct Args {
     /// Configure stdout
     #[arg(long, value_enum, value_name("cfg"), num_args(0..=1), require_equals(true), default_missing_value("allow"), help_heading = "Stdio")]
     stdout: Option<StdioCfg>,
+    /// Configure terminal capabilities for all stdio
+    #[arg(long, value_enum, value_name("cfg"), num_args(0..=1), require_equals(true), default_missing_value("allow"), help_heading = "Stdio")]
+    terminal: Option<TerminalCfg>,
+    /// Configure the stdin terminal capability
+    #[arg(long, value_enum, value_name("cfg"), num_args(0..=1), require_equals(true), default_missing_value("allow"), help_heading = "Stdio")]
+    terminal_stdin: Option<TerminalCfg>,
+    /// Configure the stdout terminal capability
+    #[arg(long, value_enum, value_name("cfg"), num_args(0..=1), require_equals(true), default_missing_value("allow"), help_heading = "Stdio")]
+    terminal_stdout: Option<TerminalCfg>,
+    /// Configure the stderr terminal capability
+    #[arg(long, value_enum, value_name("cfg"), num_args(0..=1), require_equals(true), default_missing_value("allow"), help_heading = "Stdio")]
+    terminal_stderr: Option<TerminalCfg>,
 
     /// WASI version to use when creating performing stubbing (ex. when creating a new stub)
     #[arg(long)]
@@ -121,6 +344,25 @@ where
     Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
 }
 
+fn parse_required(s: &str) -> Result<(String, Option<String>), Box<dyn Error + Send + Sync>> {
+    Ok(match s.split_once('=') {
+        Some((key, pattern)) => (key.to_string(), Some(pattern.to_string())),
+        None => (s.to_string(), None),
+    })
+}
+
+// parser for SECONDS[.NANOS] instants, with up to nanosecond precision
+fn parse_instant(s: &str) -> Result<(u64, u32), Box<dyn Error + Send + Sync>> {
+    let Some((seconds, fraction)) = s.split_once('.') else {
+        return Ok((s.parse()?, 0));
+    };
+    if fraction.is_empty() || fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("invalid fractional seconds in `{s}`").into());
+    }
+    let nanoseconds = format!("{fraction:0<9}").parse()?;
+    Ok((seconds.parse()?, nanoseconds))
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
@@ -139,13 +381,55 @@ fn main() -> Result<()> {
     };
 
     // clocks
-    virt_opts.clocks(args.allow_clocks.unwrap_or(allow_all));
+    let clocks = virt_opts.clocks(args.allow_clocks.unwrap_or(allow_all));
+    let (wall_seconds, wall_nanoseconds) = args.freeze_clocks.unwrap_or_default();
+    let start = FrozenClocks {
+        wall_seconds,
+        wall_nanoseconds,
+        monotonic: args.freeze_monotonic.unwrap_or(0),
+    };
+    if let Some(step) = args.simulate_clocks {
+        clocks.simulate(start, step);
+    } else if args.freeze_clocks.is_some() {
+        clocks.freeze(start.wall_seconds, start.wall_nanoseconds, start.monotonic);
+    } else if let Some(granularity) = args.clocks_granularity {
+        clocks.coarsen(granularity, args.clocks_jitter);
+    } else if args.clocks_offset.is_some() || args.clocks_rate.is_some() {
+        clocks.adjust(
+            args.clocks_offset.unwrap_or(0),
+            args.clocks_rate.unwrap_or(1.0),
+        );
+    }
+    if let Some(max_sleep) = args.max_sleep {
+        clocks.limit_sleep(max_sleep, args.max_sleep_action.unwrap_or_default());
+    }
+    if let Some(seed) = args.clocks_jitter_seed {
+        clocks.jitter_seed(seed);
+    }
 
     // http
     virt_opts.http(args.allow_http.unwrap_or(allow_all));
 
     // random
-    virt_opts.random(args.allow_random.unwrap_or(allow_all));
+    let random = virt_opts.random(args.allow_random.unwrap_or(allow_all));
+    if let Some(seed) = args.random_seed {
+        random.seed(seed);
+    } else if let Some(key) = &args.random_seed_config {
+        random.seed_from_config(key);
+    } else if let Some(path) = &args.random_record {
+        random.record(path);
+    } else if let Some(path) = &args.random_replay {
+        random.replay(path);
+    }
+    if let Some(policy) = args.random_secure {
+        random.secure(policy);
+    }
+    if let Some(policy) = args.random_insecure {
+        random.insecure(policy);
+    }
+    if let Some(policy) = args.random_insecure_seed {
+        random.insecure_seed(policy);
+    }
 
     // sockets
     virt_opts.sockets(args.allow_sockets.unwrap_or(allow_all));
@@ -157,13 +441,30 @@ fn main() -> Result<()> {
         .stdout(args.stdout.unwrap_or(stdio.clone()));
     let stderr = args.stderr.unwrap_or(stdio.clone());
 
-    if virt_opts.debug_enabled() && !matches!(stderr, StdioCfg::Allow) {
+    if virt_opts.debug_enabled() && !matches!(stderr, StdioCfg::Allow | StdioCfg::Tee) {
         bail!("Debug build requires stderr to be enabled");
     }
     virt_opts.stdio().stderr(stderr);
 
+    // terminal
+    let terminal = args.terminal.unwrap_or_default();
+    virt_opts
+        .stdio()
+        .terminal_stdin(args.terminal_stdin.unwrap_or(terminal.clone()))
+        .terminal_stdout(args.terminal_stdout.unwrap_or(terminal.clone()))
+        .terminal_stderr(args.terminal_stderr.unwrap_or(terminal));
+
     // exit
-    virt_opts.exit(args.allow_exit.unwrap_or(allow_all));
+    let exit = virt_opts.exit(args.allow_exit.unwrap_or(allow_all));
+    if let Some(mode) = args.exit_mode {
+        exit.mode(mode);
+    }
+    if let Some(status) = args.exit_success_as {
+        exit.success_as(status);
+    }
+    if let Some(status) = args.exit_failure_as {
+        exit.failure_as(status);
+    }
 
     // env options
     let env = virt_opts.env();
@@ -180,8 +481,46 @@ fn main() -> Result<()> {
             }
         }
     };
-    if let Some(env_overrides) = args.env {
-        env.overrides = env_overrides;
+    // explicit overrides take precedence over the files
+    for env_file in args.env_file.unwrap_or_default() {
+        env.overrides_file(env_file)?;
+    }
+    if let Some(env_overrides) = &args.env {
+        let env_overrides: Vec<(&str, &str)> = env_overrides
+            .iter()
+            .map(|(key, val)| (key.as_ref(), val.as_ref()))
+            .collect();
+        env.overrides(&env_overrides);
+    }
+    if let Some(interpolate_env) = args.interpolate_env {
+        env.interpolate(interpolate_env);
+    }
+    if let Some(unset_env) = args.unset_env {
+        env.unset = unset_env;
+    }
+    if let Some(secret_env) = args.secret_env {
+        env.secrets = secret_env;
+    }
+    if let Some(embed_secret_env) = args.embed_secret_env {
+        env.embed_secrets(embed_secret_env);
+    }
+    for (key, pattern) in args.require_env.unwrap_or_default() {
+        env.require(&key, pattern.as_deref());
+    }
+    if let Some(env_mappings) = args.map_env {
+        env.mappings = env_mappings;
+    }
+    if let Some(allow_args) = args.allow_args {
+        env.host_args(allow_args);
+    }
+    if let Some(prepend_args) = args.prepend_arg {
+        env.args.prepend = prepend_args;
+    }
+    if let Some(append_args) = args.arg {
+        env.args.append = append_args;
+    }
+    if let Some(cwd) = &args.cwd {
+        env.cwd(Some(cwd));
     }
 
     // config options
@@ -195,13 +534,43 @@ fn main() -> Result<()> {
         }
         None => {
             if allow_all {
-                // TODO: enable once wasi:config/store is stable
-                // config.allow_all();
+                config.allow_all();
             }
         }
     };
-    if let Some(config_overrides) = args.config {
-        config.overrides = config_overrides;
+    for config_file in args.config_file.unwrap_or_default() {
+        config.overrides_file(config_file)?;
+    }
+    if let Some(config_overrides) = &args.config {
+        let config_overrides: Vec<(&str, &str)> = config_overrides
+            .iter()
+            .map(|(key, val)| (key.as_ref(), val.as_ref()))
+            .collect();
+        config.overrides(&config_overrides);
+    }
+    if let Some(config_source) = args.config_source {
+        config.source = config_source;
+    }
+    if let Some(config_key_case) = args.config_key_case {
+        config.key_case = config_key_case;
+    }
+    if let Some(config_host_version) = args.config_host_version {
+        config.host_version(config_host_version);
+    }
+    if let Some(config_prefix) = &args.config_prefix {
+        config.prefix(config_prefix);
+    }
+    if let Some(unset_config) = args.unset_config {
+        config.unset = unset_config;
+    }
+    if let Some(secret_config) = args.secret_config {
+        config.secrets = secret_config;
+    }
+    if let Some(embed_secret_config) = args.embed_secret_config {
+        config.embed_secrets(embed_secret_config);
+    }
+    for (key, pattern) in args.require_config.unwrap_or_default() {
+        config.require(&key, pattern.as_deref());
     }
 
     // fs options
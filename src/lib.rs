@@ -5,14 +5,18 @@ use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use anyhow::{bail, Context, Result};
+use required_keys::strip_required_match;
 use semver::Version;
 use serde::Deserialize;
+use virt_clocks::create_clocks_virt;
 use virt_config::{create_config_virt, strip_config_virt};
 use virt_deny::{
     deny_clocks_virt, deny_exit_virt, deny_http_virt, deny_random_virt, deny_sockets_virt,
 };
 use virt_env::{create_env_virt, strip_env_virt};
+use virt_exit::create_exit_virt;
 use virt_io::{create_io_virt, VirtStdio};
+use virt_random::{create_random_virt, RANDOM_INTERFACES};
 use walrus_ops::strip_virt;
 use wasm_compose::composer::ComponentComposer;
 use wasm_metadata::Producers;
@@ -20,17 +24,32 @@ use wit_component::{metadata, ComponentEncoder, DecodedWasm, StringEncoding};
 use wit_parser::WorldItem;
 
 mod data;
+mod overrides_file;
+mod required_keys;
+mod secrets;
 mod stub_preview1;
+mod virt_clocks;
 mod virt_config;
 mod virt_deny;
 mod virt_env;
+mod virt_exit;
 mod virt_io;
+mod virt_random;
 mod walrus_ops;
 
+pub use required_keys::RequiredKey;
 pub use stub_preview1::stub_preview1;
-pub use virt_config::{HostConfig, VirtConfig};
-pub use virt_env::{HostEnv, VirtEnv};
-pub use virt_io::{FsEntry, StdioCfg, VirtFs, VirtualFiles};
+pub use virt_clocks::{
+    AdjustedClocks, ClocksMode, CoarsenedClocks, FrozenClocks, SimulatedClocks, SleepLimit,
+    SleepLimitAction, VirtClocks,
+};
+pub use virt_config::{ConfigSource, ConfigVersion, HostConfig, KeyCase, VirtConfig};
+pub use virt_env::{HostEnv, InitialCwd, VirtArgs, VirtEnv};
+pub use virt_exit::{ExitMode, ExitStatus, VirtExit};
+pub use virt_io::{FsEntry, StdioCfg, TerminalCfg, VirtFs, VirtualFiles};
+pub use virt_random::{
+    RandomMode, RandomPolicy, RecordRandom, ReplayRandom, SeededRandom, VirtRandom,
+};
 
 const VIRT_ADAPTER_0_2_1: &[u8] = include_bytes!("../lib/virtual_adapter-wasi0_2_1.wasm");
 const VIRT_ADAPTER_DEBUG_0_2_1: &[u8] =
@@ -64,15 +83,18 @@ pub(crate) type WITInterfaceNameParts =
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct WasiVirt {
     /// Exit virtualization (`wasi:cli/exit`)
-    pub(crate) exit: Option<bool>,
+    #[serde(default, deserialize_with = "virt_exit::deserialize_exit")]
+    pub(crate) exit: Option<VirtExit>,
     /// Clocks virtualization
-    pub(crate) clocks: Option<bool>,
+    #[serde(default, deserialize_with = "virt_clocks::deserialize_clocks")]
+    pub(crate) clocks: Option<VirtClocks>,
     /// Http virtualization
     pub(crate) http: Option<bool>,
     /// Sockets virtualization
     pub(crate) sockets: Option<bool>,
     /// Random virtualization
-    pub(crate) random: Option<bool>,
+    #[serde(default, deserialize_with = "virt_random::deserialize_random")]
+    pub(crate) random: Option<VirtRandom>,
 
     /// Environment virtualization
     pub(crate) env: Option<VirtEnv>,
@@ -137,13 +159,25 @@ impl WasiVirt {
     }
 
     /// Set whether `wasi:cli/exit` should be virtualized
-    pub fn exit(&mut self, virtualize: bool) {
-        self.exit = Some(virtualize);
+    pub fn exit(&mut self, virtualize: bool) -> &mut VirtExit {
+        let exit = self.exit.get_or_insert_with(Default::default);
+        exit.mode = if virtualize {
+            ExitMode::Allow
+        } else {
+            ExitMode::Deny
+        };
+        exit
     }
 
     /// Enable/disable virtualization of `wasi:clocks`
-    pub fn clocks(&mut self, virtualize: bool) {
-        self.clocks = Some(virtualize);
+    pub fn clocks(&mut self, virtualize: bool) -> &mut VirtClocks {
+        let clocks = self.clocks.get_or_insert_with(Default::default);
+        clocks.mode = if virtualize {
+            ClocksMode::Allow
+        } else {
+            ClocksMode::Deny
+        };
+        clocks
     }
 
     /// Enable/disable virtualization of `wasi:http`
@@ -157,8 +191,14 @@ impl WasiVirt {
     }
 
     /// Enable/disable virtualization of `wasi:random`
-    pub fn random(&mut self, virtualize: bool) {
-        self.random = Some(virtualize);
+    pub fn random(&mut self, virtualize: bool) -> &mut VirtRandom {
+        let random = self.random.get_or_insert_with(Default::default);
+        random.mode = if virtualize {
+            RandomMode::Allow
+        } else {
+            RandomMode::Deny
+        };
+        random
     }
 
     /// Enable/disable optimization via `wasm_opt`
@@ -208,8 +248,7 @@ impl WasiVirt {
         self.exit(true);
         self.random(true);
         self.env().allow_all();
-        // TODO enable once wasi:config/store is stable
-        // self.config().allow_all();
+        self.config().allow_all();
         self.fs().allow_host_preopens();
         self.stdio().allow();
     }
@@ -222,8 +261,7 @@ impl WasiVirt {
         self.exit(false);
         self.random(false);
         self.env().deny_all();
-        // TODO enable once wasi:config/store is stable
-        // self.config().deny_all();
+        self.config().deny_all();
         self.fs().deny_host_preopens();
         self.stdio().ignore();
     }
@@ -265,10 +303,17 @@ impl WasiVirt {
         if !found_prefixes.contains("wasi:cli/environment") {
             self.env = None;
         }
-        if !found_prefixes.contains("wasi:config/store") {
+        // the random seed may be read from the config
+        let random_seed_from_config = self
+            .random
+            .as_ref()
+            .is_some_and(|random| random.seed_config_key().is_some());
+        if !found_prefixes.contains("wasi:config/store") && !random_seed_from_config {
             self.config = None;
         }
-        if !found_prefixes.contains("wasi:filesystem/") {
+        // the random data may be recorded to the host filesystem
+        let random_records = self.random.as_ref().is_some_and(|random| random.records());
+        if !found_prefixes.contains("wasi:filesystem/") && !random_records {
             self.fs = None;
         }
         if !found_prefixes.contains("wasi:cli/std") && !found_prefixes.contains("wasi:cli/terminal")
@@ -325,13 +370,21 @@ impl WasiVirt {
         module.name = Some("wasi_virt".into());
 
         // only env virtualization is independent of io
+        let mut runtime_patterns = false;
         if let Some(env) = &self.env {
-            create_env_virt(&mut module, env, &insert_wasi_version)
+            if let (InitialCwd::Virtual(cwd), Some(fs)) = (&env.cwd, &self.fs) {
+                fs.validate_dir(cwd)
+                    .with_context(|| format!("invalid initial working directory [{cwd}]"))?;
+            }
+            runtime_patterns |= create_env_virt(&mut module, env, &insert_wasi_version)
                 .context("failed to virtualize environment")?;
         }
         if let Some(config) = &self.config {
-            create_config_virt(&mut module, config).context("failed to virtualize config")?;
+            runtime_patterns |=
+                create_config_virt(&mut module, config).context("failed to virtualize config")?;
         }
+        strip_required_match(&mut module, runtime_patterns)
+            .context("failed to strip required pattern matching")?;
 
         let virtual_files = if self.has_virtualized_io() {
             // io virt is managed through a singular io configuration
@@ -408,6 +461,9 @@ impl WasiVirt {
         let exit_world = resolve
             .select_world(pkg_id, Some("virtual-exit"))
             .context("failed to select `virtual-exit` world")?;
+        let exit_status_world = resolve
+            .select_world(pkg_id, Some("virtual-exit-status"))
+            .context("failed to select `virtual-exit-status` world")?;
         let fs_world = resolve
             .select_world(pkg_id, Some("virtual-fs"))
             .context("failed to select `virtual-fs` world")?;
@@ -417,6 +473,9 @@ impl WasiVirt {
         let stdio_world = resolve
             .select_world(pkg_id, Some("virtual-stdio"))
             .context("failed to select `virtual-stdio` world")?;
+        let stdio_capture_world = resolve
+            .select_world(pkg_id, Some("virtual-stdio-capture"))
+            .context("failed to select `virtual-stdio-capture` world")?;
         let clocks_world = resolve
             .select_world(pkg_id, Some("virtual-clocks"))
             .context("failed to select `virtual-clocks` world")?;
@@ -428,7 +487,13 @@ impl WasiVirt {
             .context("failed to select `virtual-sockets` world")?;
 
         // Process `wasi:environment`
-        if self.env.is_some() {
+        // (config read from the environment relies on the environment virtualization,
+        // which passes through the host environment by default)
+        let config_from_env = self
+            .config
+            .as_ref()
+            .is_some_and(|config| config.source == ConfigSource::Env);
+        if self.env.is_some() || config_from_env {
             resolve
                 .merge_worlds(env_world, base_world)
                 .context("failed to merge with environment world")?;
@@ -447,25 +512,80 @@ impl WasiVirt {
         }
 
         // Process `wasi:cli/exit`
-        if let Some(exit) = self.exit {
-            if !exit {
-                resolve
-                    .merge_worlds(exit_world, base_world)
-                    .context("failed to merge with exit world")?;
-                deny_exit_virt(&mut module, &insert_wasi_version)
-                    .context("failed to deny exit exports")?;
+        if let Some(exit) = &self.exit {
+            match exit.mode {
+                ExitMode::Allow if exit.codes().is_none() => {
+                    strip_virt(&mut module, &["wasi:cli/exit@"])
+                        .context("failed to strip exit exports")?;
+                }
+                ExitMode::Deny => {
+                    if exit.codes().is_some() {
+                        bail!("remapping the exit status requires an exit mode other than deny");
+                    }
+                    resolve
+                        .merge_worlds(exit_world, base_world)
+                        .context("failed to merge with exit world")?;
+                    deny_exit_virt(&mut module, &insert_wasi_version)
+                        .context("failed to deny exit exports")?;
+                }
+                ExitMode::Allow | ExitMode::Trap | ExitMode::Record => {
+                    resolve
+                        .merge_worlds(exit_world, base_world)
+                        .context("failed to merge with exit world")?;
+                    create_exit_virt(&mut module, exit)
+                        .context("failed to create exit virtualization")?;
+                }
             }
+        } else {
+            strip_virt(&mut module, &["wasi:cli/exit@"]).context("failed to strip exit exports")?;
+        }
+
+        // The exit status is only exported when it is recorded
+        if self
+            .exit
+            .as_ref()
+            .is_some_and(|exit| exit.mode == ExitMode::Record)
+        {
+            resolve
+                .merge_worlds(exit_status_world, base_world)
+                .context("failed to merge with exit status world")?;
+        } else {
+            strip_virt(&mut module, &["local:virt/exit-status"])
+                .context("failed to strip exit status exports")?;
         }
 
         // Process `wasi:random`
-        if let Some(random) = self.random {
-            if !random {
+        if let Some(random) = &self.random {
+            let policies = random.policies()?;
+            if policies.iter().all(|policy| *policy == RandomPolicy::Allow) {
+                strip_virt(&mut module, &["wasi:random/"])
+                    .context("failed to strip random exports")?;
+            } else {
+                if random.seed_config_key().is_some() && self.config.is_none() {
+                    bail!("reading the random seed from the config requires config virtualization");
+                }
+                if random.records() && !self.fs.as_ref().is_some_and(|fs| fs.host_preopens) {
+                    bail!("recording random data requires filesystem virtualization with host preopens");
+                }
+                // allowed interfaces pass through the virtualization to the host
                 resolve
                     .merge_worlds(random_world, base_world)
                     .context("failed to merge with random world")?;
-                deny_random_virt(&mut module, &insert_wasi_version)
-                    .context("failed to deny random exports")?;
+                let denied: Vec<&str> = RANDOM_INTERFACES
+                    .into_iter()
+                    .zip(policies)
+                    .filter(|(_, policy)| *policy == RandomPolicy::Deny)
+                    .map(|(iface, _)| iface)
+                    .collect();
+                if !denied.is_empty() {
+                    deny_random_virt(&mut module, &denied, &insert_wasi_version)
+                        .context("failed to deny random exports")?;
+                }
+                create_random_virt(&mut module, random)
+                    .context("failed to create random virtualization")?;
             }
+        } else {
+            strip_virt(&mut module, &["wasi:random/"]).context("failed to strip random exports")?;
         }
 
         // I/O subsystems have I/O dependence due to streams + poll
@@ -479,21 +599,36 @@ impl WasiVirt {
         }
 
         // Process clocks
-        if let Some(clocks) = self.clocks {
-            if !clocks {
-                // deny is effectively virtualization
-                // in future with fine-grained virtualization options, they
-                // also would extend here (ie !clocks is deceiving)
-                resolve
-                    .merge_worlds(clocks_world, base_world)
-                    .context("failed to merge with clock world")?;
-                deny_clocks_virt(&mut module, &insert_wasi_version)
-                    .context("failed to deny clock exports")?;
-            } else {
-                // passthrough can be simplified to just rewrapping io interfaces
-                resolve
-                    .merge_worlds(io_clocks_world, base_world)
-                    .context("failed to merge I/O clocks world")?;
+        if let Some(clocks) = &self.clocks {
+            match clocks.mode {
+                ClocksMode::Deny => {
+                    // deny is effectively virtualization
+                    resolve
+                        .merge_worlds(clocks_world, base_world)
+                        .context("failed to merge with clock world")?;
+                    deny_clocks_virt(&mut module, &insert_wasi_version)
+                        .context("failed to deny clock exports")?;
+                }
+                ClocksMode::Allow => {
+                    // passthrough can be simplified to just rewrapping io interfaces
+                    resolve
+                        .merge_worlds(io_clocks_world, base_world)
+                        .context("failed to merge I/O clocks world")?;
+                    strip_virt(&mut module, &["wasi:clocks/wall-clock"])
+                        .context("failed to strip wall clock exports")?;
+                    create_clocks_virt(&mut module, clocks)
+                        .context("failed to create clocks virtualization")?;
+                }
+                ClocksMode::Frozen(_)
+                | ClocksMode::Simulated(_)
+                | ClocksMode::Adjusted(_)
+                | ClocksMode::Coarsened(_) => {
+                    resolve
+                        .merge_worlds(clocks_world, base_world)
+                        .context("failed to merge with clock world")?;
+                    create_clocks_virt(&mut module, clocks)
+                        .context("failed to create clocks virtualization")?;
+                }
             }
         } else {
             strip_virt(&mut module, &["wasi:clocks/"]).context("failed to strip clock exports")?;
@@ -545,6 +680,16 @@ impl WasiVirt {
                 .context("failed to strip CLI exports")?;
         }
 
+        // Captured output is only exported when a stream is captured
+        if self.stdio.as_ref().is_some_and(|stdio| stdio.has_capture()) {
+            resolve
+                .merge_worlds(stdio_capture_world, base_world)
+                .context("failed to merge with stdio capture world")?;
+        } else {
+            strip_virt(&mut module, &["local:virt/captured-output"])
+                .context("failed to strip captured output exports")?;
+        }
+
         // Stdio may use FS, so enable when stdio is present
         if self.fs.is_some() || self.stdio.is_some() {
             resolve.merge_worlds(fs_world, base_world)?;
@@ -0,0 +1,164 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+/// Read key and value overrides from a `.toml`, `.json` or dotenv file
+///
+/// When `flatten` is set, nested tables are flattened into dotted keys,
+/// otherwise only top-level scalar values are supported.
+pub(crate) fn read_overrides_file(path: &Path, flatten: bool) -> Result<Vec<(String, String)>> {
+    let source = fs::read_to_string(path)
+        .with_context(|| format!("failed to read overrides file [{}]", path.display()))?;
+    let mut overrides = Vec::new();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => {
+            let table: toml::Table = toml::from_str(&source)
+                .with_context(|| format!("failed to parse TOML file [{}]", path.display()))?;
+            for (key, value) in table {
+                flatten_toml(&mut overrides, key, value, flatten)?;
+            }
+        }
+        Some("json") => {
+            let serde_json::Value::Object(object) = serde_json::from_str(&source)
+                .with_context(|| format!("failed to parse JSON file [{}]", path.display()))?
+            else {
+                bail!("JSON file [{}] must contain an object", path.display());
+            };
+            for (key, value) in object {
+                flatten_json(&mut overrides, key, value, flatten)?;
+            }
+        }
+        _ => {
+            for (idx, line) in source.lines().enumerate() {
+                if let Some(entry) = parse_dotenv_line(line)
+                    .with_context(|| format!("invalid line {} in [{}]", idx + 1, path.display()))?
+                {
+                    overrides.push(entry);
+                }
+            }
+        }
+    }
+    Ok(overrides)
+}
+
+/// Set an override, replacing any existing override for the same key
+pub(crate) fn set_override(overrides: &mut Vec<(String, String)>, key: String, value: String) {
+    match overrides.iter_mut().find(|(existing, _)| *existing == key) {
+        Some((_, existing_value)) => *existing_value = value,
+        None => overrides.push((key, value)),
+    }
+}
+
+fn flatten_toml(
+    overrides: &mut Vec<(String, String)>,
+    key: String,
+    value: toml::Value,
+    flatten: bool,
+) -> Result<()> {
+    let value = match value {
+        toml::Value::String(value) => value,
+        toml::Value::Integer(value) => value.to_string(),
+        toml::Value::Float(value) => value.to_string(),
+        toml::Value::Boolean(value) => value.to_string(),
+        toml::Value::Datetime(value) => value.to_string(),
+        toml::Value::Table(table) if flatten => {
+            for (inner_key, inner_value) in table {
+                flatten_toml(
+                    overrides,
+                    format!("{key}.{inner_key}"),
+                    inner_value,
+                    flatten,
+                )?;
+            }
+            return Ok(());
+        }
+        toml::Value::Table(_) => bail!("nested tables are not supported for [{key}]"),
+        toml::Value::Array(_) => bail!("arrays are not supported for [{key}]"),
+    };
+    overrides.push((key, value));
+    Ok(())
+}
+
+fn flatten_json(
+    overrides: &mut Vec<(String, String)>,
+    key: String,
+    value: serde_json::Value,
+    flatten: bool,
+) -> Result<()> {
+    let value = match value {
+        serde_json::Value::String(value) => value,
+        serde_json::Value::Number(value) => value.to_string(),
+        serde_json::Value::Bool(value) => value.to_string(),
+        serde_json::Value::Object(object) if flatten => {
+            for (inner_key, inner_value) in object {
+                flatten_json(
+                    overrides,
+                    format!("{key}.{inner_key}"),
+                    inner_value,
+                    flatten,
+                )?;
+            }
+            return Ok(());
+        }
+        serde_json::Value::Object(_) => bail!("nested objects are not supported for [{key}]"),
+        serde_json::Value::Array(_) => bail!("arrays are not supported for [{key}]"),
+        serde_json::Value::Null => bail!("null is not supported for [{key}]"),
+    };
+    overrides.push((key, value));
+    Ok(())
+}
+
+/// Parse a `KEY=VALUE` dotenv line, skipping blank lines and `#` comments
+///
+/// Values may be single quoted (verbatim) or double quoted (supporting
+/// `\n`, `\"` and `\\` escapes), with only a comment permitted after the
+/// closing quote, and the `export` prefix is ignored.
+fn parse_dotenv_line(line: &str) -> Result<Option<(String, String)>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let Some((key, value)) = line.split_once('=') else {
+        bail!("expected KEY=VALUE");
+    };
+    let key = key.trim();
+    if key.is_empty() {
+        bail!("missing key");
+    }
+    let value = value.trim();
+    let (value, rest) = if let Some(quoted) = value.strip_prefix('\'') {
+        let Some(end) = quoted.find('\'') else {
+            bail!("unterminated single quoted value for [{key}]");
+        };
+        (quoted[..end].to_string(), &quoted[end + 1..])
+    } else if let Some(quoted) = value.strip_prefix('"') {
+        let mut unescaped = String::new();
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('n') => unescaped.push('\n'),
+                    Some(c) => unescaped.push(c),
+                    None => bail!("unterminated double quoted value for [{key}]"),
+                },
+                Some(c) => unescaped.push(c),
+                None => bail!("unterminated double quoted value for [{key}]"),
+            }
+        }
+        (unescaped, chars.as_str())
+    } else {
+        // unquoted values end at an inline comment
+        match value.find(" #") {
+            Some(end) => (value[..end].trim_end().to_string(), ""),
+            None => (value.to_string(), ""),
+        }
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        bail!("unexpected text [{rest}] after the quoted value for [{key}]");
+    }
+    Ok(Some((key.to_string(), value)))
+}
@@ -0,0 +1,107 @@
+use anyhow::{bail, Context, Result};
+use regex_lite::Regex;
+use serde::Deserialize;
+use walrus::{FunctionBuilder, FunctionKind, Module};
+
+use crate::secrets::redact;
+
+/// A key that must be provided to the guest
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RequiredKey {
+    /// The key as seen by the guest
+    pub key: String,
+    /// A regular expression which the whole value must match
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+impl RequiredKey {
+    /// Require a key, optionally with a value pattern
+    pub fn new(key: &str, pattern: Option<&str>) -> Self {
+        RequiredKey {
+            key: key.to_string(),
+            pattern: pattern.map(|pattern| pattern.to_string()),
+        }
+    }
+}
+
+/// Check the required keys against the overrides, returning the keys
+/// which are left to be checked against the host at runtime
+///
+/// Keys which are not overridden must be permitted by `host_provides`.
+/// The values of keys matching `secrets` are redacted from the errors.
+/// When `interpolate` is set, override values containing `${VAR}`
+/// references are only known at runtime.
+pub(crate) fn check_required_keys<'a>(
+    kind: &str,
+    required: &'a [RequiredKey],
+    overrides: &[(String, String)],
+    secrets: &[String],
+    interpolate: bool,
+    host_provides: impl Fn(&str) -> bool,
+) -> Result<Vec<&'a RequiredKey>> {
+    let mut runtime_required = Vec::new();
+    for required_key in required {
+        let RequiredKey { key, pattern } = required_key;
+        let regex = match pattern {
+            // the pattern is anchored as in the adapter
+            Some(pattern) => Some(
+                Regex::new(&format!("^(?:{pattern})$"))
+                    .with_context(|| format!("invalid pattern for required {kind} [{key}]"))?,
+            ),
+            None => None,
+        };
+        match overrides
+            .iter()
+            .find(|(override_key, _)| override_key == key)
+        {
+            Some((_, value)) if interpolate && value.contains('$') => {
+                runtime_required.push(required_key);
+            }
+            Some((_, value)) => {
+                if let Some(regex) = regex {
+                    if !regex.is_match(value) {
+                        bail!(
+                            "{kind} [{key}] value [{}] does not match the required pattern [{}]",
+                            redact(secrets, key, value),
+                            pattern.as_deref().unwrap_or_default()
+                        );
+                    }
+                }
+            }
+            None if host_provides(key) => runtime_required.push(required_key),
+            None => {
+                bail!("required {kind} [{key}] is neither overridden nor permitted from the host")
+            }
+        }
+    }
+    Ok(runtime_required)
+}
+
+/// Remove the adapter export matching required value patterns
+///
+/// When no patterns are left to be checked at runtime, the matching is
+/// stubbed so that the regex engine is removed from the adapter.
+pub(crate) fn strip_required_match(module: &mut Module, runtime_patterns: bool) -> Result<()> {
+    let fid = module
+        .exports
+        .get_func("virt_required_match")
+        .context("Adapter 'virt_required_match' is not exported")?;
+    if !runtime_patterns {
+        let ty = module.types.get(module.funcs.get(fid).ty());
+        let (params, results) = (ty.params().to_vec(), ty.results().to_vec());
+        let FunctionKind::Local(func) = &module.funcs.get(fid).kind else {
+            bail!("Adapter 'virt_required_match' is not a local function");
+        };
+        let args = func.args.clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, &params, &results);
+        builder.func_body().unreachable();
+        module.funcs.get_mut(fid).kind = FunctionKind::Local(builder.local_func(args));
+    }
+    module
+        .exports
+        .remove("virt_required_match")
+        .context("failed to strip function [virt_required_match]")?;
+    Ok(())
+}
@@ -0,0 +1,17 @@
+use crate::virt_env::glob_match;
+
+/// Whether a key matches any of the secret keys or `*` patterns
+pub(crate) fn is_secret(secrets: &[String], key: &str) -> bool {
+    secrets.iter().any(|pattern| glob_match(pattern, key))
+}
+
+/// The value to display in errors, redacting the values of secret keys
+///
+/// This must match the adapter redaction of the debug output.
+pub(crate) fn redact<'a>(secrets: &[String], key: &str, value: &'a str) -> &'a str {
+    if is_secret(secrets, key) {
+        "<redacted>"
+    } else {
+        value
+    }
+}
@@ -0,0 +1,388 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+use walrus::{ir::Value, ConstExpr, ExportItem, GlobalKind, Module};
+
+use crate::walrus_ops::{get_active_data_segment, stub_virt};
+
+const CLOCKS_MODE_HOST: u8 = 0;
+const CLOCKS_MODE_FROZEN: u8 = 1;
+const CLOCKS_MODE_SIMULATED: u8 = 2;
+const CLOCKS_MODE_ADJUSTED: u8 = 3;
+const CLOCKS_MODE_COARSENED: u8 = 4;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VirtClocks {
+    /// How clock readings are provided to the guest
+    #[serde(default)]
+    pub mode: ClocksMode,
+    /// Limit the duration the guest may wait on monotonic clock timers
+    #[serde(default)]
+    pub sleep_limit: Option<SleepLimit>,
+    /// The seed of the coarsened clocks jitter, for reproducible builds
+    ///
+    /// Without a seed, the jitter is seeded from the OS randomness when
+    /// virtualizing. As the seed is embedded in the virtualization, a fixed
+    /// seed lets anyone with the seed predict the jitter.
+    #[serde(default)]
+    pub jitter_seed: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum ClocksMode {
+    /// Pass through the host clocks
+    #[default]
+    Allow,
+    /// Deny access to the clocks, trapping on any clock call
+    Deny,
+    /// Report fixed clock instants, without reading the host clocks
+    Frozen(FrozenClocks),
+    /// Simulate the clocks deterministically, without reading the host clocks
+    Simulated(SimulatedClocks),
+    /// Pass through the host clocks, with an offset wall clock and a dilated
+    /// monotonic clock
+    Adjusted(AdjustedClocks),
+    /// Pass through the host clocks, rounded down to a coarser granularity
+    /// to mitigate timing side channels
+    Coarsened(CoarsenedClocks),
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FrozenClocks {
+    /// The wall-clock seconds since the Unix epoch
+    #[serde(default)]
+    pub wall_seconds: u64,
+    /// The wall-clock nanoseconds within the second
+    #[serde(default)]
+    pub wall_nanoseconds: u32,
+    /// The monotonic clock instant in nanoseconds
+    #[serde(default)]
+    pub monotonic: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SimulatedClocks {
+    /// The starting clock instants
+    #[serde(default)]
+    pub start: FrozenClocks,
+    /// The nanoseconds the clocks advance on each monotonic clock reading
+    #[serde(default)]
+    pub step: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AdjustedClocks {
+    /// The seconds added to the host wall clock, which may be negative
+    #[serde(default)]
+    pub wall_offset: i64,
+    /// How many times faster the monotonic clock runs than the host clock,
+    /// with monotonic clock timers dilated to match
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+}
+
+impl Default for AdjustedClocks {
+    fn default() -> Self {
+        AdjustedClocks {
+            wall_offset: 0,
+            rate: default_rate(),
+        }
+    }
+}
+
+fn default_rate() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CoarsenedClocks {
+    /// The granularity in nanoseconds the clock readings are rounded down to,
+    /// which is also reported as the clock resolution
+    pub granularity: u64,
+    /// Randomly jitter the rounding, so that the exact moments the clocks
+    /// tick over cannot be observed
+    #[serde(default)]
+    pub jitter: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SleepLimit {
+    /// The longest wait in nanoseconds permitted to the guest
+    pub max: u64,
+    /// The action for waits beyond the limit
+    #[serde(default)]
+    pub action: SleepLimitAction,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SleepLimitAction {
+    /// Clamp the wait to the limit
+    #[default]
+    Clamp,
+    /// Resolve the wait immediately
+    Ready,
+    /// Trap, with a message when stdio is virtualized
+    Trap,
+}
+
+impl VirtClocks {
+    /// Freeze the clocks at the given wall-clock time since the Unix epoch
+    /// and monotonic clock instant in nanoseconds
+    ///
+    /// Monotonic clock timers resolve immediately, as time never advances.
+    pub fn freeze(
+        &mut self,
+        wall_seconds: u64,
+        wall_nanoseconds: u32,
+        monotonic: u64,
+    ) -> &mut Self {
+        self.mode = ClocksMode::Frozen(FrozenClocks {
+            wall_seconds,
+            wall_nanoseconds,
+            monotonic,
+        });
+        self
+    }
+
+    /// Simulate the clocks from the given starting instants, advancing them
+    /// by `step` nanoseconds on each monotonic clock reading
+    ///
+    /// Monotonic clock timers jump the clocks forward when the guest waits
+    /// on them, instead of waiting on the host.
+    pub fn simulate(&mut self, start: FrozenClocks, step: u64) -> &mut Self {
+        self.mode = ClocksMode::Simulated(SimulatedClocks { start, step });
+        self
+    }
+
+    /// Pass through the host clocks, offsetting the wall clock by
+    /// `wall_offset` seconds and running the monotonic clock `rate` times
+    /// faster than the host clock
+    ///
+    /// For example, a rate of 10 makes sleeps complete ten times faster.
+    pub fn adjust(&mut self, wall_offset: i64, rate: f64) -> &mut Self {
+        self.mode = ClocksMode::Adjusted(AdjustedClocks { wall_offset, rate });
+        self
+    }
+
+    /// Limit the duration the guest may wait on monotonic clock timers to
+    /// `max` nanoseconds, applying the action to longer waits
+    ///
+    /// With adjusted clocks, the limit applies to the host time a wait takes
+    /// rather than the scaled duration seen by the guest.
+    pub fn limit_sleep(&mut self, max: u64, action: SleepLimitAction) -> &mut Self {
+        self.sleep_limit = Some(SleepLimit { max, action });
+        self
+    }
+
+    /// Pass through the host clocks, rounded down to the given granularity
+    /// in nanoseconds, optionally with random jitter
+    pub fn coarsen(&mut self, granularity: u64, jitter: bool) -> &mut Self {
+        self.mode = ClocksMode::Coarsened(CoarsenedClocks {
+            granularity,
+            jitter,
+        });
+        self
+    }
+
+    /// Seed the coarsened clocks jitter, so that the same seed always
+    /// produces the same virtualization
+    ///
+    /// Without a seed, the jitter is seeded from the OS randomness when
+    /// virtualizing, which should be preferred unless builds must be
+    /// reproducible, as anyone with the seed can predict the jitter.
+    pub fn jitter_seed(&mut self, seed: u64) -> &mut Self {
+        self.jitter_seed = Some(seed);
+        self
+    }
+}
+
+/// Clocks may also be configured as a boolean, allowing or denying the host clocks
+pub(crate) fn deserialize_clocks<'de, D>(deserializer: D) -> Result<Option<VirtClocks>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ClocksCfg {
+        Enabled(bool),
+        Virt(VirtClocks),
+    }
+    Ok(Some(match ClocksCfg::deserialize(deserializer)? {
+        ClocksCfg::Enabled(true) => VirtClocks::default(),
+        ClocksCfg::Enabled(false) => VirtClocks {
+            mode: ClocksMode::Deny,
+            ..Default::default()
+        },
+        ClocksCfg::Virt(clocks) => clocks,
+    }))
+}
+
+/// The initial xorshift state for a jitter seed, which must not be zero as
+/// zero disables the jitter in the adapter
+fn jitter_state(seed: u64) -> u64 {
+    // SplitMix64, so that nearby seeds produce unrelated states
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    match z ^ (z >> 31) {
+        0 => 0x9e3779b97f4a7c15,
+        state => state,
+    }
+}
+
+pub(crate) fn create_clocks_virt(module: &mut Module, clocks: &VirtClocks) -> Result<()> {
+    let clocks_ptr_addr = {
+        let clocks_ptr_export = module
+            .exports
+            .iter()
+            .find(|expt| expt.name.as_str() == "clocks")
+            .context("Adapter 'clocks' is not exported")?;
+        let ExportItem::Global(clocks_ptr_global) = clocks_ptr_export.item else {
+            bail!("Adapter 'clocks' not a global");
+        };
+        let GlobalKind::Local(ConstExpr::Value(Value::I32(clocks_ptr_addr))) =
+            &module.globals.get(clocks_ptr_global).kind
+        else {
+            bail!("Adapter 'clocks' not a local I32 global value");
+        };
+        *clocks_ptr_addr as u32
+    };
+
+    // the static field values, with the defaults for fields unused by the mode
+    let mut start = FrozenClocks::default();
+    let mut step = 0;
+    let mut adjusted = AdjustedClocks::default();
+    let mut granularity = 0;
+    let mut jitter_seed = 0;
+    if clocks.jitter_seed.is_some()
+        && !matches!(&clocks.mode, ClocksMode::Coarsened(coarsened) if coarsened.jitter)
+    {
+        bail!("a jitter seed requires coarsened clocks with jitter");
+    }
+    let mode = match &clocks.mode {
+        ClocksMode::Deny => return Ok(()),
+        ClocksMode::Allow if clocks.sleep_limit.is_none() => return Ok(()),
+        ClocksMode::Allow => CLOCKS_MODE_HOST,
+        ClocksMode::Frozen(frozen) => {
+            start = frozen.clone();
+            CLOCKS_MODE_FROZEN
+        }
+        ClocksMode::Simulated(simulated) => {
+            start = simulated.start.clone();
+            step = simulated.step;
+            CLOCKS_MODE_SIMULATED
+        }
+        ClocksMode::Adjusted(adjusted_clocks) => {
+            if !adjusted_clocks.rate.is_finite() || adjusted_clocks.rate <= 0.0 {
+                bail!(
+                    "clock rate [{}] must be a positive number",
+                    adjusted_clocks.rate
+                );
+            }
+            adjusted = adjusted_clocks.clone();
+            CLOCKS_MODE_ADJUSTED
+        }
+        ClocksMode::Coarsened(coarsened) => {
+            if coarsened.granularity == 0 {
+                bail!("clock granularity must be at least a nanosecond");
+            }
+            granularity = coarsened.granularity;
+            if coarsened.jitter {
+                let seed = match clocks.jitter_seed {
+                    Some(seed) => seed,
+                    None => getrandom::u64().context("failed to seed the clocks jitter")?,
+                };
+                jitter_seed = jitter_state(seed);
+            }
+            CLOCKS_MODE_COARSENED
+        }
+    };
+    if start.wall_nanoseconds >= 1_000_000_000 {
+        bail!(
+            "wall-clock nanoseconds [{}] must be less than a second",
+            start.wall_nanoseconds
+        );
+    }
+
+    // frozen and simulated clocks never read the host clocks
+    if mode == CLOCKS_MODE_FROZEN || mode == CLOCKS_MODE_SIMULATED {
+        stub_virt(module, &["wasi:clocks/"], false)?;
+    }
+
+    // In the existing static data segment, update the static data options.
+    //
+    // From virtual-adapter/src/clocks.rs:
+    //
+    // #[repr(C)]
+    // pub struct Clocks {
+    //     /// The clocks mode
+    //     /// [byte 0]
+    //     mode: u8,
+    //     /// The frozen or starting wall-clock nanoseconds
+    //     /// [byte 4]
+    //     wall_nanoseconds: u32,
+    //     /// The frozen or starting wall-clock seconds since the Unix epoch
+    //     /// [byte 8]
+    //     wall_seconds: u64,
+    //     /// The frozen or starting monotonic clock instant in nanoseconds
+    //     /// [byte 16]
+    //     monotonic: u64,
+    //     /// The nanoseconds the simulated clocks advance on each monotonic clock reading
+    //     /// [byte 24]
+    //     step: u64,
+    //     /// How many times faster the adjusted monotonic clock runs than the host clock
+    //     /// [byte 32]
+    //     rate: f64,
+    //     /// The seconds added to the adjusted host wall clock
+    //     /// [byte 40]
+    //     wall_offset: i64,
+    //     /// The granularity in nanoseconds the coarsened host clocks are rounded down to
+    //     /// [byte 48]
+    //     granularity: u64,
+    //     /// The seed for the coarsened clocks jitter, with zero disabling jitter
+    //     /// [byte 56]
+    //     jitter_seed: u64,
+    //     /// The longest monotonic clock wait in nanoseconds permitted to the guest
+    //     /// [byte 64]
+    //     sleep_limit: u64,
+    //     /// The action for waits beyond the sleep limit
+    //     /// [byte 72]
+    //     sleep_limit_action: u8,
+    // }
+    let memory = module.get_memory_id()?;
+    let (data, data_offset) = get_active_data_segment(module, memory, clocks_ptr_addr)?;
+
+    // the zero-initialized static may lie beyond the end of the data segment
+    const CLOCKS_STATIC_LEN: usize = 80;
+    if data.value.len() < data_offset + CLOCKS_STATIC_LEN {
+        data.value.resize(data_offset + CLOCKS_STATIC_LEN, 0);
+    }
+
+    let bytes = data.value.as_mut_slice();
+
+    bytes[data_offset] = mode;
+    bytes[data_offset + 4..data_offset + 8].copy_from_slice(&start.wall_nanoseconds.to_le_bytes());
+    bytes[data_offset + 8..data_offset + 16].copy_from_slice(&start.wall_seconds.to_le_bytes());
+    bytes[data_offset + 16..data_offset + 24].copy_from_slice(&start.monotonic.to_le_bytes());
+    bytes[data_offset + 24..data_offset + 32].copy_from_slice(&step.to_le_bytes());
+    bytes[data_offset + 32..data_offset + 40].copy_from_slice(&adjusted.rate.to_le_bytes());
+    bytes[data_offset + 40..data_offset + 48].copy_from_slice(&adjusted.wall_offset.to_le_bytes());
+    bytes[data_offset + 48..data_offset + 56].copy_from_slice(&granularity.to_le_bytes());
+    bytes[data_offset + 56..data_offset + 64].copy_from_slice(&jitter_seed.to_le_bytes());
+    let (sleep_limit, sleep_limit_action) = match &clocks.sleep_limit {
+        Some(SleepLimit { max, action }) => (*max, *action as u8),
+        None => (u64::MAX, 0),
+    };
+    bytes[data_offset + 64..data_offset + 72].copy_from_slice(&sleep_limit.to_le_bytes());
+    bytes[data_offset + 72] = sleep_limit_action;
+
+    Ok(())
+}
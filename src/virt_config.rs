@@ -1,7 +1,14 @@
+use std::path::Path;
+
 use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
 use serde::Deserialize;
 use walrus::{ir::Value, ConstExpr, DataKind, ExportItem, GlobalKind, Module};
 
+use crate::overrides_file::{read_overrides_file, set_override};
+use crate::required_keys::{check_required_keys, RequiredKey};
+use crate::secrets::is_secret;
+use crate::virt_env::{glob_match, split_patterns};
 use crate::walrus_ops::{bump_stack_global, get_active_data_segment};
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -14,6 +21,94 @@ pub struct VirtConfig {
     /// (Pass-through / encapsulate / allow / deny)
     #[serde(default)]
     pub host: HostConfig,
+    /// Configuration properties to hide from the guest,
+    /// regardless of the host allow or deny lists
+    #[serde(default)]
+    pub unset: Vec<String>,
+    /// Scope the host configuration to a namespace, such that guest keys
+    /// are read from host keys with this prefix (as in `billing.`)
+    pub prefix: Option<String>,
+    /// Configuration properties which must be provided to the guest
+    ///
+    /// Overridden properties are checked when virtualizing, while properties
+    /// from the host are checked by the adapter on first access, returning
+    /// an error when missing or not matching their pattern.
+    #[serde(default)]
+    pub required: Vec<RequiredKey>,
+    /// Configuration property keys or `*` patterns holding secrets
+    ///
+    /// Secret values are redacted from the debug output and virtualization
+    /// errors. Overrides of secret keys are rejected, as their values would
+    /// be embedded in the adapter data as is, unless `embed_secrets` is set.
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Allow overrides of secret keys, embedding their values in the adapter
+    /// data as is, such that the virtualization must be kept as private as
+    /// the values
+    #[serde(default)]
+    pub embed_secrets: bool,
+    /// Where the host configuration properties are read from
+    #[serde(default)]
+    pub source: ConfigSource,
+    /// How host environment variable names are transformed into
+    /// configuration property keys, when reading from the environment
+    #[serde(default)]
+    pub key_case: KeyCase,
+    /// The `wasi:config/store` version imported from the host
+    ///
+    /// All supported versions are exported to the guest, translating
+    /// between the guest and host versions as necessary.
+    #[serde(default)]
+    pub host_version: ConfigVersion,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum ConfigVersion {
+    /// `wasi:config/store@0.2.0-draft`
+    #[default]
+    #[serde(rename = "0.2.0-draft")]
+    #[value(name = "0.2.0-draft")]
+    Draft,
+    /// `wasi:config/store@0.2.0-rc.1`
+    #[serde(rename = "0.2.0-rc.1")]
+    #[value(name = "0.2.0-rc.1")]
+    Rc1,
+}
+
+impl ConfigVersion {
+    /// All of the supported `wasi:config/store` versions
+    const ALL: [ConfigVersion; 2] = [ConfigVersion::Draft, ConfigVersion::Rc1];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConfigVersion::Draft => "0.2.0-draft",
+            ConfigVersion::Rc1 => "0.2.0-rc.1",
+        }
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfigSource {
+    /// Read configuration properties from the host `wasi:config/store`
+    #[default]
+    Store,
+    /// Read configuration properties from the environment variables,
+    /// for hosts that only support `wasi:cli/environment`
+    Env,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyCase {
+    /// Use environment variable names as configuration keys verbatim
+    #[default]
+    Verbatim,
+    /// Lowercase environment variable names (`DB_URL` is read as `db_url`)
+    Lower,
+    /// Lowercase environment variable names, replacing `_` with `.`
+    /// (`DB_URL` is read as `db.url`)
+    LowerDotted,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -27,8 +122,10 @@ pub enum HostConfig {
     /// properties from the host
     All,
     /// Only allow the provided configuration property keys
+    /// (keys may contain `*` wildcards, as in `feature.*`)
     Allow(Vec<String>),
     /// Allow all configuration properties, except the provided keys
+    /// (keys may contain `*` wildcards, as in `*.secret`)
     Deny(Vec<String>),
 }
 
@@ -58,15 +155,78 @@ impl VirtConfig {
     }
 
     /// Set the configuration property overrides
+    ///
+    /// An override for a key which is already overridden replaces the
+    /// existing value in place rather than adding a second override.
     pub fn overrides(&mut self, overrides: &[(&str, &str)]) -> &mut Self {
         for (key, val) in overrides {
-            self.overrides.push((key.to_string(), val.to_string()));
+            set_override(&mut self.overrides, key.to_string(), val.to_string());
         }
         self
     }
+
+    /// Load configuration property overrides from a `.toml`, `.json` or dotenv file
+    ///
+    /// Overrides from the file replace any existing overrides of the same key.
+    /// Nested tables are flattened into dotted keys.
+    pub fn overrides_file(&mut self, path: impl AsRef<Path>) -> Result<&mut Self> {
+        for (key, value) in read_overrides_file(path.as_ref(), true)? {
+            set_override(&mut self.overrides, key, value);
+        }
+        Ok(self)
+    }
+
+    /// Scope the host configuration properties to the given key prefix
+    pub fn prefix(&mut self, prefix: &str) -> &mut Self {
+        self.prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Read the host configuration properties from the environment variables
+    ///
+    /// The prefix, if any, is stripped from the environment variable names
+    /// before applying the key case transform.
+    pub fn from_env(&mut self, key_case: KeyCase) -> &mut Self {
+        self.source = ConfigSource::Env;
+        self.key_case = key_case;
+        self
+    }
+
+    /// Set the `wasi:config/store` version imported from the host
+    pub fn host_version(&mut self, version: ConfigVersion) -> &mut Self {
+        self.host_version = version;
+        self
+    }
+
+    /// Hide configuration properties from the guest
+    pub fn unset(&mut self, keys: &[&str]) -> &mut Self {
+        self.unset.extend(keys.iter().map(|s| s.to_string()));
+        self
+    }
+
+    /// Mark configuration property keys or `*` patterns as secret
+    pub fn secret(&mut self, keys: &[&str]) -> &mut Self {
+        self.secrets.extend(keys.iter().map(|s| s.to_string()));
+        self
+    }
+
+    /// Allow overrides of secret keys, embedding their values in the virtualization as is
+    pub fn embed_secrets(&mut self, enable: bool) -> &mut Self {
+        self.embed_secrets = enable;
+        self
+    }
+
+    /// Require a configuration property, optionally with a value pattern
+    /// which the whole value must match
+    pub fn require(&mut self, key: &str, pattern: Option<&str>) -> &mut Self {
+        self.required.push(RequiredKey::new(key, pattern));
+        self
+    }
 }
 
-pub(crate) fn create_config_virt<'a>(module: &'a mut Module, config: &VirtConfig) -> Result<()> {
+/// Virtualize the configuration, returning whether any required value
+/// patterns are left to be checked at runtime
+pub(crate) fn create_config_virt<'a>(module: &'a mut Module, config: &VirtConfig) -> Result<bool> {
     let config_ptr_addr = {
         let config_ptr_export = module
             .exports
@@ -84,11 +244,38 @@ pub(crate) fn create_config_virt<'a>(module: &'a mut Module, config: &VirtConfig
         *config_ptr_addr as u32
     };
 
-    // If host config is disabled, remove its import entirely
-    // replacing it with a stub panic
-    if matches!(config.host, HostConfig::None) {
-        stub_config_virt(module)?;
-        // we do arguments as well because virt assumes reactors for now...
+    // If host config is disabled or read from the environment, remove its
+    // import entirely replacing it with a stub panic
+    if matches!(config.host, HostConfig::None) || config.source == ConfigSource::Env {
+        stub_config_virt(module, &ConfigVersion::ALL)?;
+    } else {
+        // only the configured host version is imported
+        let unused_versions: Vec<ConfigVersion> = ConfigVersion::ALL
+            .into_iter()
+            .filter(|version| *version != config.host_version)
+            .collect();
+        stub_config_virt(module, &unused_versions)?;
+    }
+
+    for key in &config.unset {
+        if config
+            .overrides
+            .iter()
+            .any(|(override_key, _)| override_key == key)
+        {
+            bail!("config property [{key}] cannot be both overridden and unset");
+        }
+    }
+    if !config.embed_secrets {
+        if let Some((key, _)) = config
+            .overrides
+            .iter()
+            .find(|(key, _)| is_secret(&config.secrets, key))
+        {
+            bail!(
+                "secret config property [{key}] cannot be overridden without embedding its value in the virtualization, which must be enabled explicitly"
+            );
+        }
     }
 
     let memory = module.get_memory_id()?;
@@ -102,22 +289,46 @@ pub(crate) fn create_config_virt<'a>(module: &'a mut Module, config: &VirtConfig
         field_data_vec.push(key.as_ref());
         field_data_vec.push(value.as_ref());
     }
-    match &config.host {
-        HostConfig::Allow(allow_list) => {
-            let mut allow_list: Vec<&str> = allow_list.iter().map(|item| item.as_ref()).collect();
-            allow_list.sort();
-            for key in allow_list {
-                field_data_vec.push(key);
+    // exact keys are binary searched, so patterns are kept separately after them
+    let (keys, patterns) = match &config.host {
+        HostConfig::Allow(list) | HostConfig::Deny(list) => split_patterns(list),
+        _ => (Vec::new(), Vec::new()),
+    };
+    field_data_vec.extend(&keys);
+    field_data_vec.extend(&patterns);
+    let mut sorted_unset: Vec<&str> = config.unset.iter().map(|key| key.as_ref()).collect();
+    sorted_unset.sort();
+    field_data_vec.extend(&sorted_unset);
+    if let Some(prefix) = &config.prefix {
+        field_data_vec.push(prefix);
+    }
+    let in_list =
+        |key: &str| keys.contains(&key) || patterns.iter().any(|pattern| glob_match(pattern, key));
+    let required = check_required_keys(
+        "config property",
+        &config.required,
+        &config.overrides,
+        &config.secrets,
+        false,
+        |key| {
+            if config.unset.iter().any(|unset_key| unset_key == key) {
+                return false;
             }
-        }
-        HostConfig::Deny(deny_list) => {
-            let mut deny_list: Vec<&str> = deny_list.iter().map(|item| item.as_ref()).collect();
-            deny_list.sort();
-            for key in deny_list {
-                field_data_vec.push(key);
+            match &config.host {
+                HostConfig::None => false,
+                HostConfig::All => true,
+                // as in the adapter, matching both the exact keys and the patterns
+                HostConfig::Allow(_) => in_list(key),
+                HostConfig::Deny(_) => !in_list(key),
             }
-        }
-        _ => {}
+        },
+    )?;
+    for RequiredKey { key, pattern } in &required {
+        field_data_vec.push(key);
+        field_data_vec.push(pattern.as_deref().unwrap_or_default());
+    }
+    for secret in &config.secrets {
+        field_data_vec.push(secret);
     }
 
     let mut field_data_bytes = Vec::new();
@@ -168,6 +379,13 @@ pub(crate) fn create_config_virt<'a>(module: &'a mut Module, config: &VirtConfig
     //     /// on the fallback lookups
     //     /// [byte 1]
     //     host_fallback_allow: bool,
+    //     /// Whether to read host properties from the environment variables
+    //     /// [byte 2]
+    //     host_source_env: bool,
+    //     /// Key case transform for environment variable names
+    //     /// (0 = verbatim, 1 = lower, 2 = lower dotted)
+    //     /// [byte 3]
+    //     host_env_key_case: u8,
     //     /// How many host fields are defined in the data pointer
     //     /// [byte 4]
     //     host_field_cnt: u32,
@@ -179,6 +397,29 @@ pub(crate) fn create_config_virt<'a>(module: &'a mut Module, config: &VirtConfig
     //     /// up to the lengths previously provided.
     //     /// [byte 12]
     //     host_field_data: *const u8,
+    //     /// How many configuration properties are unset
+    //     /// (these follow the allow or deny patterns in the data)
+    //     /// [byte 16]
+    //     unset_cnt: u32,
+    //     /// How many of the allow or deny entries are `*` patterns
+    //     /// (these follow the exact allow or deny keys in the data)
+    //     /// [byte 20]
+    //     host_allow_or_deny_pattern_cnt: u32,
+    //     /// Whether a host namespace prefix follows the unset keys
+    //     /// [byte 24]
+    //     host_prefix_cnt: u32,
+    //     /// The `wasi:config/store` version imported from the host
+    //     /// (0 = 0.2.0-draft, 1 = 0.2.0-rc.1)
+    //     /// [byte 28]
+    //     host_store_version: u8,
+    //     /// How many required keys and value patterns are defined
+    //     /// (these follow the prefix in the data)
+    //     /// [byte 32]
+    //     required_cnt: u32,
+    //     /// How many secret keys or patterns are defined
+    //     /// (these follow the required keys in the data)
+    //     /// [byte 36]
+    //     secret_cnt: u32,
     // }
     let (data, data_offset) = get_active_data_segment(module, memory, config_ptr_addr)?;
     let bytes = data.value.as_mut_slice();
@@ -191,40 +432,60 @@ pub(crate) fn create_config_virt<'a>(module: &'a mut Module, config: &VirtConfig
         HostConfig::None => {
             bytes[data_offset] = 0;
         }
-        HostConfig::Allow(allow_list) => {
+        HostConfig::Allow(_) => {
             bytes[data_offset + 1] = 1;
-            bytes[data_offset + 8..data_offset + 12]
-                .copy_from_slice(&(allow_list.len() as u32).to_le_bytes());
         }
-        HostConfig::Deny(deny_list) => {
+        HostConfig::Deny(_) => {
             bytes[data_offset + 1] = 0;
-            bytes[data_offset + 8..data_offset + 12]
-                .copy_from_slice(&(deny_list.len() as u32).to_le_bytes());
         }
     };
+    bytes[data_offset + 8..data_offset + 12].copy_from_slice(&(keys.len() as u32).to_le_bytes());
+    bytes[data_offset + 20..data_offset + 24]
+        .copy_from_slice(&(patterns.len() as u32).to_le_bytes());
+    bytes[data_offset + 2] = (config.source == ConfigSource::Env) as u8;
+    bytes[data_offset + 3] = match config.key_case {
+        KeyCase::Verbatim => 0,
+        KeyCase::Lower => 1,
+        KeyCase::LowerDotted => 2,
+    };
+    bytes[data_offset + 24..data_offset + 28]
+        .copy_from_slice(&(config.prefix.is_some() as u32).to_le_bytes());
+    bytes[data_offset + 16..data_offset + 20]
+        .copy_from_slice(&(config.unset.len() as u32).to_le_bytes());
+    bytes[data_offset + 28] = match config.host_version {
+        ConfigVersion::Draft => 0,
+        ConfigVersion::Rc1 => 1,
+    };
+    bytes[data_offset + 32..data_offset + 36]
+        .copy_from_slice(&(required.len() as u32).to_le_bytes());
+    bytes[data_offset + 36..data_offset + 40]
+        .copy_from_slice(&(config.secrets.len() as u32).to_le_bytes());
     if let Some(field_data_addr) = field_data_addr {
         bytes[data_offset + 12..data_offset + 16].copy_from_slice(&field_data_addr.to_le_bytes());
     }
 
-    Ok(())
+    Ok(required.iter().any(|required| required.pattern.is_some()))
 }
 
 /// Functions that represent the configuration functionality provided by WASI CLI
 const WASI_CONFIG_FNS: [&str; 2] = ["get", "get-all"];
 
 /// Stub imported functions that implement the WASI config functionality
+/// for the given `wasi:config/store` versions
 ///
 /// This function throws an error if any imported functions do not exist
-pub(crate) fn stub_config_virt(module: &mut Module) -> Result<()> {
-    for fn_name in WASI_CONFIG_FNS {
-        module.replace_imported_func(
-            module
-                .imports
-                .get_func("wasi:config/store@0.2.0-draft", fn_name)?,
-            |(body, _)| {
-                body.unreachable();
-            },
-        )?;
+pub(crate) fn stub_config_virt(module: &mut Module, versions: &[ConfigVersion]) -> Result<()> {
+    for version in versions {
+        for fn_name in WASI_CONFIG_FNS {
+            module.replace_imported_func(
+                module
+                    .imports
+                    .get_func(format!("wasi:config/store@{}", version.as_str()), fn_name)?,
+                |(body, _)| {
+                    body.unreachable();
+                },
+            )?;
+        }
     }
 
     Ok(())
@@ -232,18 +493,20 @@ pub(crate) fn stub_config_virt(module: &mut Module) -> Result<()> {
 
 /// Strip exported functions that implement the WASI config functionality
 pub(crate) fn strip_config_virt(module: &mut Module) -> Result<()> {
-    stub_config_virt(module)?;
+    stub_config_virt(module, &ConfigVersion::ALL)?;
 
-    for fn_name in WASI_CONFIG_FNS {
-        let Ok(fid) = module
-            .exports
-            .get_func(format!("wasi:config/store@0.2.0-draft#{fn_name}"))
-        else {
-            bail!("Expected Config function {fn_name}")
-        };
-        module.replace_exported_func(fid, |(body, _)| {
-            body.unreachable();
-        })?;
+    for version in ConfigVersion::ALL {
+        for fn_name in WASI_CONFIG_FNS {
+            let Ok(fid) = module
+                .exports
+                .get_func(format!("wasi:config/store@{}#{fn_name}", version.as_str()))
+            else {
+                bail!("Expected Config function {fn_name}")
+            };
+            module.replace_exported_func(fid, |(body, _)| {
+                body.unreachable();
+            })?;
+        }
     }
 
     Ok(())
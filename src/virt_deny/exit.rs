@@ -5,6 +5,7 @@ use semver::Version;
 use walrus::{FuncParams, FuncResults, Module, ValType};
 
 use super::replace_or_insert_stub_for_exports;
+use crate::walrus_ops::stub_virt;
 use crate::WITInterfaceNameParts;
 
 /// Functions that represent the environment functionality provided by WASI exits
@@ -25,5 +26,6 @@ fn get_wasi_exit_fns() -> &'static Vec<(WITInterfaceNameParts, FuncParams, FuncR
 /// * `insert_wasi_version` - version of WASI to use when inserting stubs
 ///
 pub(crate) fn deny_exit_virt(module: &mut Module, insert_wasi_version: &Version) -> Result<()> {
+    stub_virt(module, &["wasi:cli/exit@"], false)?;
     replace_or_insert_stub_for_exports(module, get_wasi_exit_fns(), insert_wasi_version)
 }
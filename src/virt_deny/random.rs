@@ -5,6 +5,7 @@ use semver::Version;
 use walrus::{FuncParams, FuncResults, Module, ValType};
 
 use super::replace_or_insert_stub_for_exports;
+use crate::walrus_ops::stub_virt;
 use crate::WITInterfaceNameParts;
 
 /// Functions that represent the environment functionality provided by WASI randoms
@@ -64,8 +65,22 @@ fn get_wasi_random_fns() -> &'static Vec<(WITInterfaceNameParts, FuncParams, Fun
 /// # Arguments
 ///
 /// * `module` - The module to deny
+/// * `interfaces` - The `wasi:random` interfaces to deny (e.g. `insecure-seed`)
 /// * `insert_wasi_version` - version of WASI to use when inserting stubs
 ///
-pub(crate) fn deny_random_virt(module: &mut Module, insert_wasi_version: &Version) -> Result<()> {
-    replace_or_insert_stub_for_exports(module, get_wasi_random_fns(), insert_wasi_version)
+pub(crate) fn deny_random_virt(
+    module: &mut Module,
+    interfaces: &[&str],
+    insert_wasi_version: &Version,
+) -> Result<()> {
+    for iface in interfaces {
+        stub_virt(module, &[&format!("wasi:random/{iface}@")], false)?;
+    }
+    replace_or_insert_stub_for_exports(
+        module,
+        get_wasi_random_fns()
+            .iter()
+            .filter(|((_, _, iface, _), _, _)| interfaces.contains(iface)),
+        insert_wasi_version,
+    )
 }
@@ -1,8 +1,13 @@
+use std::path::Path;
+
 use anyhow::{bail, Context, Result};
 use semver::Version;
 use serde::Deserialize;
 use walrus::{ir::Value, ConstExpr, DataKind, ExportItem, GlobalKind, Module};
 
+use crate::overrides_file::{read_overrides_file, set_override};
+use crate::required_keys::{check_required_keys, RequiredKey};
+use crate::secrets::is_secret;
 use crate::walrus_ops::{bump_stack_global, get_active_data_segment};
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -11,10 +16,54 @@ pub struct VirtEnv {
     /// Set specific environment variable overrides
     #[serde(default)]
     pub overrides: Vec<(String, String)>,
+    /// Resolve `${VAR}` references to host environment variables in the
+    /// override values at runtime
+    ///
+    /// `$$` produces a literal `$`. References to host variables that are
+    /// missing, unset or not permitted by the host policy resolve to an empty string.
+    #[serde(default)]
+    pub interpolate: bool,
     /// Define how to embed into the host environment
     /// (Pass-through / encapsulate / allow / deny)
     #[serde(default)]
     pub host: HostEnv,
+    /// Expose host environment variables under a different name,
+    /// as (guest key, host key) pairs
+    ///
+    /// Mapped host variables are only visible under their guest key,
+    /// independent of the host allow or deny lists. Each guest key and each
+    /// host key may only be mapped once.
+    #[serde(default)]
+    pub mappings: Vec<(String, String)>,
+    /// Environment variables to hide from the guest,
+    /// regardless of the host allow or deny lists
+    #[serde(default)]
+    pub unset: Vec<String>,
+    /// Environment variables which must be provided to the guest
+    ///
+    /// Overridden variables are checked when virtualizing, while variables
+    /// from the host are checked by the adapter on first access, trapping
+    /// with an error when missing or not matching their pattern.
+    #[serde(default)]
+    pub required: Vec<RequiredKey>,
+    /// Environment variable keys or `*` patterns holding secrets
+    ///
+    /// Secret values are redacted from the debug output and virtualization
+    /// errors. Overrides of secret keys are rejected, as their values would
+    /// be embedded in the adapter data as is, unless `embed_secrets` is set.
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Allow overrides of secret keys, embedding their values in the adapter
+    /// data as is, such that the virtualization must be kept as private as
+    /// the values
+    #[serde(default)]
+    pub embed_secrets: bool,
+    /// Command-line argument virtualization
+    #[serde(default)]
+    pub args: VirtArgs,
+    /// The initial working directory reported to the guest
+    #[serde(default)]
+    pub cwd: InitialCwd,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -28,11 +77,40 @@ pub enum HostEnv {
     /// variables from the host
     All,
     /// Only allow the provided environment variable keys
+    /// (keys may contain `*` wildcards, as in `AWS_*`)
     Allow(Vec<String>),
     /// Allow all environment variables, except the provided keys
+    /// (keys may contain `*` wildcards, as in `*_TOKEN`)
     Deny(Vec<String>),
 }
 
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VirtArgs {
+    /// Arguments inserted after the program name, ahead of the host arguments
+    #[serde(default)]
+    pub prepend: Vec<String>,
+    /// Arguments added after the host arguments
+    #[serde(default)]
+    pub append: Vec<String>,
+    /// Whether to pass through the host arguments
+    /// (by default only when the host environment is not fully encapsulated)
+    pub host: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum InitialCwd {
+    /// Pass through the host working directory
+    /// (only when the host environment is not fully encapsulated)
+    #[default]
+    Host,
+    /// Report no working directory
+    None,
+    /// Report the given absolute path within the virtual filesystem
+    Virtual(String),
+}
+
 impl VirtEnv {
     /// Set the host environment variable allow list
     pub fn allow(&mut self, allow_list: &[String]) -> &mut Self {
@@ -59,19 +137,179 @@ impl VirtEnv {
     }
 
     /// Set the environment variable overrides
+    ///
+    /// An override for a key which is already overridden replaces the
+    /// existing value in place rather than adding a second override.
     pub fn overrides(&mut self, overrides: &[(&str, &str)]) -> &mut Self {
         for (key, val) in overrides {
-            self.overrides.push((key.to_string(), val.to_string()));
+            set_override(&mut self.overrides, key.to_string(), val.to_string());
         }
         self
     }
+
+    /// Load environment variable overrides from a `.toml`, `.json` or dotenv file
+    ///
+    /// Overrides from the file replace any existing overrides of the same key.
+    pub fn overrides_file(&mut self, path: impl AsRef<Path>) -> Result<&mut Self> {
+        for (key, value) in read_overrides_file(path.as_ref(), false)? {
+            set_override(&mut self.overrides, key, value);
+        }
+        Ok(self)
+    }
+
+    /// Enable runtime interpolation of host variables into the override values
+    pub fn interpolate(&mut self, enable: bool) -> &mut Self {
+        self.interpolate = enable;
+        self
+    }
+
+    /// Expose a host environment variable to the guest under a different key
+    pub fn map(&mut self, guest_key: &str, host_key: &str) -> &mut Self {
+        self.mappings
+            .push((guest_key.to_string(), host_key.to_string()));
+        self
+    }
+
+    /// Hide environment variables from the guest
+    pub fn unset(&mut self, keys: &[&str]) -> &mut Self {
+        self.unset.extend(keys.iter().map(|s| s.to_string()));
+        self
+    }
+
+    /// Require an environment variable, optionally with a value pattern
+    /// which the whole value must match
+    pub fn require(&mut self, key: &str, pattern: Option<&str>) -> &mut Self {
+        self.required.push(RequiredKey::new(key, pattern));
+        self
+    }
+
+    /// Mark environment variable keys or `*` patterns as secret
+    pub fn secret(&mut self, keys: &[&str]) -> &mut Self {
+        self.secrets.extend(keys.iter().map(|s| s.to_string()));
+        self
+    }
+
+    /// Allow overrides of secret keys, embedding their values in the virtualization as is
+    pub fn embed_secrets(&mut self, enable: bool) -> &mut Self {
+        self.embed_secrets = enable;
+        self
+    }
+
+    /// Replace the arguments entirely, hiding the host arguments
+    ///
+    /// The first argument is the program name.
+    pub fn args(&mut self, args: &[&str]) -> &mut Self {
+        self.args.prepend = args.iter().map(|s| s.to_string()).collect();
+        self.args.append = Vec::new();
+        self.args.host = Some(false);
+        self
+    }
+
+    /// Insert arguments after the program name, ahead of the host arguments
+    pub fn prepend_args(&mut self, args: &[&str]) -> &mut Self {
+        self.args.prepend.extend(args.iter().map(|s| s.to_string()));
+        self
+    }
+
+    /// Add arguments after the host arguments
+    pub fn append_args(&mut self, args: &[&str]) -> &mut Self {
+        self.args.append.extend(args.iter().map(|s| s.to_string()));
+        self
+    }
+
+    /// Set whether the host arguments are passed through
+    pub fn host_args(&mut self, allow: bool) -> &mut Self {
+        self.args.host = Some(allow);
+        self
+    }
+
+    /// Set the initial working directory, or `None` to report no working directory
+    ///
+    /// When the filesystem is virtualized, the path must resolve to a directory
+    /// within the configured preopens.
+    pub fn cwd(&mut self, cwd: Option<&str>) -> &mut Self {
+        self.cwd = match cwd {
+            Some(cwd) => InitialCwd::Virtual(cwd.to_string()),
+            None => InitialCwd::None,
+        };
+        self
+    }
+
+    /// Whether the host arguments are passed through
+    fn host_args_enabled(&self) -> bool {
+        self.args
+            .host
+            .unwrap_or(!matches!(self.host, HostEnv::None))
+    }
+
+    /// Whether the host working directory is passed through
+    fn host_cwd_enabled(&self) -> bool {
+        matches!(self.cwd, InitialCwd::Host) && !matches!(self.host, HostEnv::None)
+    }
+}
+
+/// Check the `${VAR}` reference syntax of an interpolated value
+fn validate_interpolation(value: &str) -> Result<()> {
+    let mut rest = value;
+    while let Some(idx) = rest.find('$') {
+        rest = &rest[idx + 1..];
+        if let Some(next) = rest.strip_prefix('$') {
+            rest = next;
+        } else if let Some(next) = rest.strip_prefix('{') {
+            let Some(end) = next.find('}') else {
+                bail!("unterminated variable reference in [{value}]");
+            };
+            if end == 0 {
+                bail!("empty variable reference in [{value}]");
+            }
+            rest = &next[end + 1..];
+        }
+    }
+    Ok(())
+}
+
+/// Split an allow or deny list into sorted exact keys and sorted `*` patterns
+pub(crate) fn split_patterns(list: &[String]) -> (Vec<&str>, Vec<&str>) {
+    let (mut patterns, mut keys): (Vec<&str>, Vec<&str>) = list
+        .iter()
+        .map(|item| item.as_ref())
+        .partition(|item: &&str| item.contains('*'));
+    keys.sort();
+    patterns.sort();
+    (keys, patterns)
 }
 
+/// Match a key against a pattern where `*` matches any sequence of characters
+///
+/// This must match the adapter matching of allow, deny and secret patterns.
+pub(crate) fn glob_match(pattern: &str, key: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // the first part is anchored to the start
+    let Some(mut rest) = parts.next().and_then(|first| key.strip_prefix(first)) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // the last part is anchored to the end
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    // no wildcard, so the key must match exactly
+    rest.is_empty()
+}
+
+/// Virtualize the environment, returning whether any required value
+/// patterns are left to be checked at runtime
 pub(crate) fn create_env_virt<'a>(
     module: &'a mut Module,
     env: &VirtEnv,
     wasi_version: &Version,
-) -> Result<()> {
+) -> Result<bool> {
     let env_ptr_addr = {
         let env_ptr_export = module
             .exports
@@ -91,9 +329,59 @@ pub(crate) fn create_env_virt<'a>(
 
     // If host env is disabled, remove its import entirely
     // replacing it with a stub panic
-    if matches!(env.host, HostEnv::None) {
-        stub_env_virt(module, &wasi_version)?;
-        // we do arguments as well because virt assumes reactors for now...
+    if matches!(env.host, HostEnv::None) && env.mappings.is_empty() {
+        stub_env_virt(module, wasi_version, &["get-environment"])?;
+    }
+    let host_args = env.host_args_enabled();
+    if !host_args {
+        stub_env_virt(module, wasi_version, &["get-arguments"])?;
+    }
+    let host_cwd = env.host_cwd_enabled();
+    if !host_cwd {
+        stub_env_virt(module, wasi_version, &["initial-cwd"])?;
+    }
+    if let InitialCwd::Virtual(cwd) = &env.cwd {
+        if !cwd.starts_with('/') {
+            bail!("initial working directory [{cwd}] must be an absolute path");
+        }
+    }
+
+    for (idx, (guest_key, host_key)) in env.mappings.iter().enumerate() {
+        for (other_guest_key, other_host_key) in &env.mappings[..idx] {
+            if guest_key == other_guest_key {
+                bail!("env var [{guest_key}] is mapped more than once");
+            }
+            if host_key == other_host_key {
+                bail!("host env var [{host_key}] is mapped more than once");
+            }
+        }
+    }
+
+    for key in &env.unset {
+        if env
+            .overrides
+            .iter()
+            .any(|(override_key, _)| override_key == key)
+        {
+            bail!("env var [{key}] cannot be both overridden and unset");
+        }
+    }
+    if !env.embed_secrets {
+        if let Some((key, _)) = env
+            .overrides
+            .iter()
+            .find(|(key, _)| is_secret(&env.secrets, key))
+        {
+            bail!(
+                "secret env var [{key}] cannot be overridden without embedding its value in the virtualization, which must be enabled explicitly"
+            );
+        }
+    }
+    if env.interpolate {
+        for (key, value) in &env.overrides {
+            validate_interpolation(value)
+                .with_context(|| format!("invalid override for env var [{key}]"))?;
+        }
     }
 
     let memory = module.get_memory_id()?;
@@ -107,22 +395,62 @@ pub(crate) fn create_env_virt<'a>(
         field_data_vec.push(key.as_ref());
         field_data_vec.push(value.as_ref());
     }
-    match &env.host {
-        HostEnv::Allow(allow_list) => {
-            let mut allow_list: Vec<&str> = allow_list.iter().map(|item| item.as_ref()).collect();
-            allow_list.sort();
-            for key in allow_list {
-                field_data_vec.push(key);
+    // exact keys are binary searched, so patterns are kept separately after them
+    let (keys, patterns) = match &env.host {
+        HostEnv::Allow(list) | HostEnv::Deny(list) => split_patterns(list),
+        _ => (Vec::new(), Vec::new()),
+    };
+    field_data_vec.extend(&keys);
+    field_data_vec.extend(&patterns);
+    // mappings are sorted by host key for lookups against the host environment
+    let mut sorted_mappings = env.mappings.clone();
+    sorted_mappings.sort_by(|(_, a), (_, b)| a.cmp(b));
+    for (guest_key, host_key) in &sorted_mappings {
+        field_data_vec.push(host_key);
+        field_data_vec.push(guest_key);
+    }
+    let mut sorted_unset: Vec<&str> = env.unset.iter().map(|key| key.as_ref()).collect();
+    sorted_unset.sort();
+    field_data_vec.extend(&sorted_unset);
+    let in_list =
+        |key: &str| keys.contains(&key) || patterns.iter().any(|pattern| glob_match(pattern, key));
+    let required = check_required_keys(
+        "env var",
+        &env.required,
+        &env.overrides,
+        &env.secrets,
+        env.interpolate,
+        |key| {
+            if env.unset.iter().any(|unset_key| unset_key == key) {
+                return false;
             }
-        }
-        HostEnv::Deny(deny_list) => {
-            let mut deny_list: Vec<&str> = deny_list.iter().map(|item| item.as_ref()).collect();
-            deny_list.sort();
-            for key in deny_list {
-                field_data_vec.push(key);
+            if env.mappings.iter().any(|(guest_key, _)| guest_key == key) {
+                return true;
             }
-        }
-        _ => {}
+            match &env.host {
+                HostEnv::None => false,
+                HostEnv::All => true,
+                // as in the adapter, matching both the exact keys and the patterns
+                HostEnv::Allow(_) => in_list(key),
+                HostEnv::Deny(_) => !in_list(key),
+            }
+        },
+    )?;
+    for RequiredKey { key, pattern } in &required {
+        field_data_vec.push(key);
+        field_data_vec.push(pattern.as_deref().unwrap_or_default());
+    }
+    for secret in &env.secrets {
+        field_data_vec.push(secret);
+    }
+    for arg in &env.args.prepend {
+        field_data_vec.push(arg);
+    }
+    for arg in &env.args.append {
+        field_data_vec.push(arg);
+    }
+    if let InitialCwd::Virtual(cwd) = &env.cwd {
+        field_data_vec.push(cwd);
     }
 
     let mut field_data_bytes = Vec::new();
@@ -173,6 +501,12 @@ pub(crate) fn create_env_virt<'a>(
     //     /// on the fallback lookups
     //     /// [byte 1]
     //     host_fallback_allow: bool,
+    //     /// Whether to pass through the host arguments
+    //     /// [byte 2]
+    //     host_args: bool,
+    //     /// Whether to pass through the host working directory
+    //     /// [byte 3]
+    //     host_cwd: bool,
     //     /// How many host fields are defined in the data pointer
     //     /// [byte 4]
     //     host_field_cnt: u32,
@@ -184,10 +518,53 @@ pub(crate) fn create_env_virt<'a>(
     //     /// up to the lengths previously provided.
     //     /// [byte 12]
     //     host_field_data: *const u8,
+    //     /// How many arguments to insert after the program name
+    //     /// (these follow the allow or deny keys in the data)
+    //     /// [byte 16]
+    //     args_prepend_cnt: u32,
+    //     /// How many arguments to add after the host arguments
+    //     /// (these follow the prepended arguments in the data)
+    //     /// [byte 20]
+    //     args_append_cnt: u32,
+    //     /// Whether a virtual working directory follows the arguments
+    //     /// [byte 24]
+    //     cwd_cnt: u32,
+    //     /// How many of the allow or deny entries are `*` patterns
+    //     /// (these follow the exact allow or deny keys in the data)
+    //     /// [byte 28]
+    //     host_allow_or_deny_pattern_cnt: u32,
+    //     /// How many host to guest key mappings are defined
+    //     /// (these follow the allow or deny patterns in the data)
+    //     /// [byte 32]
+    //     host_mapping_cnt: u32,
+    //     /// Whether to interpolate host variables into the override values
+    //     /// [byte 36]
+    //     interpolate: bool,
+    //     /// How many environment variables are unset
+    //     /// (these follow the mappings in the data)
+    //     /// [byte 40]
+    //     unset_cnt: u32,
+    //     /// How many required keys and value patterns are defined
+    //     /// (these follow the unset keys in the data)
+    //     /// [byte 44]
+    //     required_cnt: u32,
+    //     /// How many secret keys or patterns are defined
+    //     /// (these follow the required keys in the data)
+    //     /// [byte 48]
+    //     secret_cnt: u32,
     // }
     let (data, data_offset) = get_active_data_segment(module, memory, env_ptr_addr)?;
     let bytes = data.value.as_mut_slice();
 
+    bytes[data_offset + 2] = host_args as u8;
+    bytes[data_offset + 3] = host_cwd as u8;
+    bytes[data_offset + 16..data_offset + 20]
+        .copy_from_slice(&(env.args.prepend.len() as u32).to_le_bytes());
+    bytes[data_offset + 20..data_offset + 24]
+        .copy_from_slice(&(env.args.append.len() as u32).to_le_bytes());
+    let cwd_cnt = matches!(env.cwd, InitialCwd::Virtual(_)) as u32;
+    bytes[data_offset + 24..data_offset + 28].copy_from_slice(&cwd_cnt.to_le_bytes());
+
     let host_field_cnt = env.overrides.len() as u32;
     bytes[data_offset + 4..data_offset + 8].copy_from_slice(&host_field_cnt.to_le_bytes());
     match &env.host {
@@ -196,22 +573,30 @@ pub(crate) fn create_env_virt<'a>(
         HostEnv::None => {
             bytes[data_offset] = 0;
         }
-        HostEnv::Allow(allow_list) => {
+        HostEnv::Allow(_) => {
             bytes[data_offset + 1] = 1;
-            bytes[data_offset + 8..data_offset + 12]
-                .copy_from_slice(&(allow_list.len() as u32).to_le_bytes());
         }
-        HostEnv::Deny(deny_list) => {
+        HostEnv::Deny(_) => {
             bytes[data_offset + 1] = 0;
-            bytes[data_offset + 8..data_offset + 12]
-                .copy_from_slice(&(deny_list.len() as u32).to_le_bytes());
         }
     };
+    bytes[data_offset + 8..data_offset + 12].copy_from_slice(&(keys.len() as u32).to_le_bytes());
+    bytes[data_offset + 28..data_offset + 32]
+        .copy_from_slice(&(patterns.len() as u32).to_le_bytes());
+    bytes[data_offset + 32..data_offset + 36]
+        .copy_from_slice(&(env.mappings.len() as u32).to_le_bytes());
+    bytes[data_offset + 36] = env.interpolate as u8;
+    bytes[data_offset + 40..data_offset + 44]
+        .copy_from_slice(&(env.unset.len() as u32).to_le_bytes());
+    bytes[data_offset + 44..data_offset + 48]
+        .copy_from_slice(&(required.len() as u32).to_le_bytes());
+    bytes[data_offset + 48..data_offset + 52]
+        .copy_from_slice(&(env.secrets.len() as u32).to_le_bytes());
     if let Some(field_data_addr) = field_data_addr {
         bytes[data_offset + 12..data_offset + 16].copy_from_slice(&field_data_addr.to_le_bytes());
     }
 
-    Ok(())
+    Ok(required.iter().any(|required| required.pattern.is_some()))
 }
 
 /// Functions that represent the environment functionality provided by WASI CLI
@@ -220,8 +605,12 @@ const WASI_ENV_FNS: [&str; 3] = ["get-arguments", "get-environment", "initial-cw
 /// Stub imported functions that implement the WASI CLI environment functionality
 ///
 /// This function throws an error if any imported functions do not exist
-pub(crate) fn stub_env_virt(module: &mut Module, wasi_version: &Version) -> Result<()> {
-    for fn_name in WASI_ENV_FNS {
+pub(crate) fn stub_env_virt(
+    module: &mut Module,
+    wasi_version: &Version,
+    fn_names: &[&str],
+) -> Result<()> {
+    for fn_name in fn_names {
         module.replace_imported_func(
             module
                 .imports
@@ -237,7 +626,7 @@ pub(crate) fn stub_env_virt(module: &mut Module, wasi_version: &Version) -> Resu
 
 /// Strip exported functions that implement the WASI CLI environment functionality
 pub(crate) fn strip_env_virt(module: &mut Module, wasi_version: &Version) -> Result<()> {
-    stub_env_virt(module, wasi_version)?;
+    stub_env_virt(module, wasi_version, &WASI_ENV_FNS)?;
 
     for fn_name in WASI_ENV_FNS {
         let Ok(fid) = module
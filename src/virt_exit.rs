@@ -0,0 +1,163 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+use walrus::{ir::Value, ConstExpr, ExportItem, GlobalKind, Module};
+
+use crate::walrus_ops::{get_active_data_segment, stub_virt};
+
+const EXIT_MODE_HOST: u8 = 0;
+const EXIT_MODE_TRAP: u8 = 1;
+const EXIT_MODE_RECORD: u8 = 2;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VirtExit {
+    /// How guest calls to `wasi:cli/exit` are handled
+    #[serde(default)]
+    pub mode: ExitMode,
+    /// The status reported when the guest exits with success
+    pub success_as: Option<ExitStatus>,
+    /// The status reported when the guest exits with failure
+    pub failure_as: Option<ExitStatus>,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitStatus {
+    /// Report the exit as success, with the exit code 0
+    Success,
+    /// Report the exit as failure, with the exit code 1
+    Failure,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitMode {
+    /// Exit the host instance
+    #[default]
+    Allow,
+    /// Trap without a message, as for any denied call
+    Deny,
+    /// Trap, with a message including the status when stdio is virtualized
+    Trap,
+    /// Return from the exit call to the guest, recording the status for the
+    /// exported `local:virt/exit-status` accessor
+    Record,
+}
+
+impl VirtExit {
+    /// Set how guest calls to `wasi:cli/exit` are handled
+    pub fn mode(&mut self, mode: ExitMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Report a successful guest exit with the given status
+    pub fn success_as(&mut self, status: ExitStatus) -> &mut Self {
+        self.success_as = Some(status);
+        self
+    }
+
+    /// Report a failed guest exit with the given status
+    pub fn failure_as(&mut self, status: ExitStatus) -> &mut Self {
+        self.failure_as = Some(status);
+        self
+    }
+
+    /// The reported exit codes for guest success and failure, when either is remapped
+    pub(crate) fn codes(&self) -> Option<[u8; 2]> {
+        if self.success_as.is_none() && self.failure_as.is_none() {
+            return None;
+        }
+        let code = |status| match status {
+            ExitStatus::Success => 0,
+            ExitStatus::Failure => 1,
+        };
+        Some([
+            code(self.success_as.unwrap_or(ExitStatus::Success)),
+            code(self.failure_as.unwrap_or(ExitStatus::Failure)),
+        ])
+    }
+}
+
+/// Exit may also be configured as a boolean, allowing or denying the host exit
+pub(crate) fn deserialize_exit<'de, D>(deserializer: D) -> Result<Option<VirtExit>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ExitCfg {
+        Enabled(bool),
+        Virt(VirtExit),
+    }
+    Ok(Some(match ExitCfg::deserialize(deserializer)? {
+        ExitCfg::Enabled(true) => VirtExit::default(),
+        ExitCfg::Enabled(false) => VirtExit {
+            mode: ExitMode::Deny,
+            ..Default::default()
+        },
+        ExitCfg::Virt(exit) => exit,
+    }))
+}
+
+pub(crate) fn create_exit_virt(module: &mut Module, exit: &VirtExit) -> Result<()> {
+    let exit_ptr_addr = {
+        let exit_ptr_export = module
+            .exports
+            .iter()
+            .find(|expt| expt.name.as_str() == "exit")
+            .context("Adapter 'exit' is not exported")?;
+        let ExportItem::Global(exit_ptr_global) = exit_ptr_export.item else {
+            bail!("Adapter 'exit' not a global");
+        };
+        let GlobalKind::Local(ConstExpr::Value(Value::I32(exit_ptr_addr))) =
+            &module.globals.get(exit_ptr_global).kind
+        else {
+            bail!("Adapter 'exit' not a local I32 global value");
+        };
+        *exit_ptr_addr as u32
+    };
+
+    let mode = match exit.mode {
+        ExitMode::Deny => return Ok(()),
+        ExitMode::Allow => EXIT_MODE_HOST,
+        ExitMode::Trap => EXIT_MODE_TRAP,
+        ExitMode::Record => EXIT_MODE_RECORD,
+    };
+
+    // the guest only exits the host instance in the allow mode
+    if mode != EXIT_MODE_HOST {
+        stub_virt(module, &["wasi:cli/exit@"], false)?;
+    }
+
+    // In the existing static data segment, update the static data options.
+    //
+    // From virtual-adapter/src/exit.rs:
+    //
+    // #[repr(C)]
+    // pub struct Exit {
+    //     /// The exit mode
+    //     /// [byte 0]
+    //     mode: u8,
+    //     /// The reported exit codes for guest success and failure
+    //     /// [byte 1]
+    //     codes: [u8; 2],
+    // }
+    let memory = module.get_memory_id()?;
+    let (data, data_offset) = get_active_data_segment(module, memory, exit_ptr_addr)?;
+
+    // the zero-initialized static may lie beyond the end of the data segment
+    const EXIT_STATIC_LEN: usize = 3;
+    if data.value.len() < data_offset + EXIT_STATIC_LEN {
+        data.value.resize(data_offset + EXIT_STATIC_LEN, 0);
+    }
+
+    let bytes = data.value.as_mut_slice();
+    bytes[data_offset] = mode;
+    if let Some(codes) = exit.codes() {
+        bytes[data_offset + 1..data_offset + 3].copy_from_slice(&codes);
+    }
+
+    Ok(())
+}
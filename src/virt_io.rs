@@ -17,6 +17,33 @@ pub enum StdioCfg {
     Allow,
     Ignore,
     Deny,
+    /// Accumulate output in memory, readable through `local:virt/captured-output`
+    Capture,
+    /// Capture output while also forwarding it to the host
+    Tee,
+}
+
+#[derive(ValueEnum, Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TerminalCfg {
+    /// Pass through the host terminal
+    #[default]
+    Allow,
+    /// Only report a terminal when the stream itself is allowed
+    Mirror,
+    /// Never report a terminal
+    Deny,
+}
+
+impl TerminalCfg {
+    /// Whether the host terminal is exposed for a stream with the given config
+    fn enabled(&self, stream: &StdioCfg) -> bool {
+        match self {
+            TerminalCfg::Allow => true,
+            TerminalCfg::Mirror => matches!(stream, StdioCfg::Allow | StdioCfg::Tee),
+            TerminalCfg::Deny => false,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Default, Clone)]
@@ -25,6 +52,15 @@ pub struct VirtStdio {
     pub stdin: StdioCfg,
     pub stdout: StdioCfg,
     pub stderr: StdioCfg,
+    /// Terminal capability for stdin (`wasi:cli/terminal-stdin`)
+    #[serde(default)]
+    pub terminal_stdin: TerminalCfg,
+    /// Terminal capability for stdout (`wasi:cli/terminal-stdout`)
+    #[serde(default)]
+    pub terminal_stdout: TerminalCfg,
+    /// Terminal capability for stderr (`wasi:cli/terminal-stderr`)
+    #[serde(default)]
+    pub terminal_stderr: TerminalCfg,
 }
 
 impl VirtStdio {
@@ -58,6 +94,35 @@ impl VirtStdio {
         self.stderr = cfg;
         self
     }
+    pub fn capture(&mut self) -> &mut Self {
+        self.stdout = StdioCfg::Capture;
+        self.stderr = StdioCfg::Capture;
+        self
+    }
+    /// Whether any output stream is captured
+    pub(crate) fn has_capture(&self) -> bool {
+        [&self.stdout, &self.stderr]
+            .iter()
+            .any(|cfg| matches!(cfg, StdioCfg::Capture | StdioCfg::Tee))
+    }
+    pub fn terminal(&mut self, cfg: TerminalCfg) -> &mut Self {
+        self.terminal_stdin = cfg.clone();
+        self.terminal_stdout = cfg.clone();
+        self.terminal_stderr = cfg;
+        self
+    }
+    pub fn terminal_stdin(&mut self, cfg: TerminalCfg) -> &mut Self {
+        self.terminal_stdin = cfg;
+        self
+    }
+    pub fn terminal_stdout(&mut self, cfg: TerminalCfg) -> &mut Self {
+        self.terminal_stdout = cfg;
+        self
+    }
+    pub fn terminal_stderr(&mut self, cfg: TerminalCfg) -> &mut Self {
+        self.terminal_stderr = cfg;
+        self
+    }
 }
 
 #[derive(Deserialize, Debug, Default, Clone)]
@@ -128,6 +193,71 @@ impl VirtFs {
         self.preopens.insert(name, FsEntry::Virtualize(dir));
         self
     }
+    /// Check that an absolute virtual path resolves to a directory
+    /// within the configured preopens
+    pub(crate) fn validate_dir(&self, path: &str) -> Result<()> {
+        // find the most specific preopen containing the path
+        let Some((preopen, entry)) = self
+            .preopens
+            .iter()
+            .filter(|(name, _)| {
+                path.strip_prefix(name.as_str()).is_some_and(|rest| {
+                    rest.is_empty() || name.ends_with('/') || rest.starts_with('/')
+                })
+            })
+            .max_by_key(|(name, _)| name.len())
+        else {
+            // host preopens are only known at runtime
+            if self.host_preopens {
+                return Ok(());
+            }
+            bail!("path is not within any configured preopen");
+        };
+        let mut entry = entry;
+        let mut components = path[preopen.len()..]
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".");
+        while let Some(component) = components.next() {
+            if component == ".." {
+                bail!("path must be normalized");
+            }
+            match entry {
+                FsEntry::Dir(dir) => {
+                    entry = dir
+                        .get(component)
+                        .with_context(|| format!("[{component}] not found in [{preopen}]"))?;
+                }
+                FsEntry::Virtualize(host_path) => {
+                    let host_path = std::iter::once(component)
+                        .chain(components)
+                        .fold(std::path::PathBuf::from(host_path), |p, c| p.join(c));
+                    if !host_path.is_dir() {
+                        bail!("[{}] is not a directory", host_path.display());
+                    }
+                    return Ok(());
+                }
+                // runtime directories can only be checked at runtime
+                FsEntry::RuntimeDir(_) => return Ok(()),
+                FsEntry::Symlink(_) => bail!("symlinks are not supported in the path"),
+                FsEntry::File(_) | FsEntry::Source(_) | FsEntry::RuntimeFile(_) => {
+                    bail!("[{component}] is not within a directory")
+                }
+            }
+        }
+        match entry {
+            FsEntry::Dir(_) | FsEntry::RuntimeDir(_) => Ok(()),
+            FsEntry::Virtualize(host_path) => {
+                if !fs::metadata(host_path)?.is_dir() {
+                    bail!("[{host_path}] is not a directory");
+                }
+                Ok(())
+            }
+            FsEntry::Symlink(_) => bail!("symlinks are not supported in the path"),
+            FsEntry::File(_) | FsEntry::Source(_) | FsEntry::RuntimeFile(_) => {
+                bail!("path is not a directory")
+            }
+        }
+    }
     /// Set the passive cutoff size in bytes for creating Wasm passive segments
     pub fn passive_cutoff(&mut self, passive_cutoff: usize) -> &mut Self {
         self.passive_cutoff = Some(passive_cutoff);
@@ -315,6 +445,12 @@ const FLAGS_IGNORE_STDOUT: u32 = 1 << 4;
 const FLAGS_IGNORE_STDERR: u32 = 1 << 5;
 const FLAGS_HOST_PREOPENS: u32 = 1 << 6;
 const FLAGS_HOST_PASSTHROUGH: u32 = 1 << 7;
+const FLAGS_NO_TERMINAL_STDIN: u32 = 1 << 8;
+const FLAGS_NO_TERMINAL_STDOUT: u32 = 1 << 9;
+const FLAGS_NO_TERMINAL_STDERR: u32 = 1 << 10;
+const FLAGS_CAPTURE_STDOUT: u32 = 1 << 11;
+const FLAGS_CAPTURE_STDERR: u32 = 1 << 12;
+const FLAGS_HOST_STDERR: u32 = 1 << 13;
 
 pub(crate) fn create_io_virt<'a>(
     module: &'a mut Module,
@@ -339,6 +475,7 @@ pub(crate) fn create_io_virt<'a>(
             StdioCfg::Ignore => flags |= FLAGS_IGNORE_STDIN,
             // deny is the default
             StdioCfg::Deny => {}
+            StdioCfg::Capture | StdioCfg::Tee => bail!("stdin cannot be captured"),
         }
         match stdio.stdout {
             StdioCfg::Allow => {
@@ -347,6 +484,11 @@ pub(crate) fn create_io_virt<'a>(
             }
             StdioCfg::Ignore => flags |= FLAGS_IGNORE_STDOUT,
             StdioCfg::Deny => {}
+            StdioCfg::Capture => flags |= FLAGS_CAPTURE_STDOUT,
+            StdioCfg::Tee => {
+                flags |= FLAGS_CAPTURE_STDOUT | FLAGS_ENABLE_STDOUT;
+                disable_stdio = false;
+            }
         }
         match stdio.stderr {
             StdioCfg::Allow => {
@@ -355,12 +497,50 @@ pub(crate) fn create_io_virt<'a>(
             }
             StdioCfg::Ignore => flags |= FLAGS_IGNORE_STDERR,
             StdioCfg::Deny => {}
+            StdioCfg::Capture => flags |= FLAGS_CAPTURE_STDERR,
+            StdioCfg::Tee => {
+                flags |= FLAGS_CAPTURE_STDERR | FLAGS_ENABLE_STDERR;
+                disable_stdio = false;
+            }
+        }
+        if !stdio.terminal_stdin.enabled(&stdio.stdin) {
+            flags |= FLAGS_NO_TERMINAL_STDIN;
+        }
+        if !stdio.terminal_stdout.enabled(&stdio.stdout) {
+            flags |= FLAGS_NO_TERMINAL_STDOUT;
+        }
+        if !stdio.terminal_stderr.enabled(&stdio.stderr) {
+            flags |= FLAGS_NO_TERMINAL_STDERR;
         }
     } else {
         strip_virt(module, &["wasi:cli/std", "wasi:cli/terminal"])?;
     }
     if disable_stdio {
+        // without any host stdio there is no host terminal to report either
+        flags |= FLAGS_NO_TERMINAL_STDIN | FLAGS_NO_TERMINAL_STDOUT | FLAGS_NO_TERMINAL_STDERR;
         stub_virt(module, &["wasi:cli/std", "wasi:cli/terminal"], false)?;
+    } else {
+        // the host stderr remains available for debug logging
+        flags |= FLAGS_HOST_STDERR;
+        // remove the host terminal imports that can no longer be reached
+        if flags & FLAGS_NO_TERMINAL_STDIN > 0 {
+            stub_virt(
+                module,
+                &["wasi:cli/terminal-stdin", "wasi:cli/terminal-input"],
+                false,
+            )?;
+        }
+        if flags & FLAGS_NO_TERMINAL_STDOUT > 0 {
+            stub_virt(module, &["wasi:cli/terminal-stdout"], false)?;
+        }
+        if flags & FLAGS_NO_TERMINAL_STDERR > 0 {
+            stub_virt(module, &["wasi:cli/terminal-stderr"], false)?;
+        }
+        if flags & (FLAGS_NO_TERMINAL_STDOUT | FLAGS_NO_TERMINAL_STDERR)
+            == FLAGS_NO_TERMINAL_STDOUT | FLAGS_NO_TERMINAL_STDERR
+        {
+            stub_virt(module, &["wasi:cli/terminal-output"], false)?;
+        }
     }
 
     // First we iterate the options and fill in all HostDir and HostFile entries
@@ -0,0 +1,400 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+use walrus::{ir::Value, ConstExpr, DataKind, ExportItem, GlobalKind, MemoryId, Module};
+
+use crate::walrus_ops::{bump_stack_global, get_active_data_segment, stub_virt};
+
+const RANDOM_MODE_HOST: u8 = 0;
+const RANDOM_MODE_SEEDED: u8 = 1;
+const RANDOM_MODE_RECORD: u8 = 2;
+const RANDOM_MODE_REPLAY: u8 = 3;
+
+/// The `wasi:random` interfaces, in the order of their policies
+pub(crate) const RANDOM_INTERFACES: [&str; 3] = ["random", "insecure", "insecure-seed"];
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VirtRandom {
+    /// How random data is provided to the guest, for the interfaces
+    /// without a policy of their own
+    #[serde(default)]
+    pub mode: RandomMode,
+    /// The policy for the cryptographically-secure `wasi:random/random`
+    #[serde(default)]
+    pub secure: Option<RandomPolicy>,
+    /// The policy for `wasi:random/insecure`
+    #[serde(default)]
+    pub insecure: Option<RandomPolicy>,
+    /// The policy for `wasi:random/insecure-seed`
+    #[serde(default)]
+    pub insecure_seed: Option<RandomPolicy>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum RandomMode {
+    /// Pass through the host random sources
+    #[default]
+    Allow,
+    /// Deny access to random data, trapping on any random call
+    Deny,
+    /// Generate all random data deterministically from a seed, without
+    /// reading the host random sources
+    Seeded(SeededRandom),
+    /// Pass through the host random sources, recording all random data
+    /// returned to the guest to a log file
+    Record(RecordRandom),
+    /// Replay the random data of a recorded log, without reading the host
+    /// random sources
+    Replay(ReplayRandom),
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SeededRandom {
+    /// The seed of the generator
+    #[serde(default)]
+    pub seed: u64,
+    /// Read the seed from this configuration property at runtime instead,
+    /// which requires configuration virtualization
+    #[serde(default)]
+    pub seed_config_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RecordRandom {
+    /// The path of the log file under the host preopens, which the random
+    /// data is appended to at runtime
+    pub path: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ReplayRandom {
+    /// The path of the recorded log file, which is embedded into the
+    /// virtualization
+    pub path: PathBuf,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RandomPolicy {
+    /// Pass through the host random source
+    Allow,
+    /// Trap on any call
+    Deny,
+    /// Generate the random data from the seed of the seeded mode
+    Seeded,
+    /// Record the random data to the log file of the record mode
+    Record,
+    /// Replay the random data from the log file of the replay mode
+    Replay,
+}
+
+impl VirtRandom {
+    /// Generate all random data from a generator with the given seed
+    ///
+    /// The same seed always produces the same sequence of random data, which
+    /// is not suitable for cryptographic use.
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.mode = RandomMode::Seeded(SeededRandom {
+            seed,
+            seed_config_key: None,
+        });
+        self
+    }
+
+    /// Generate all random data from a generator seeded at runtime from the
+    /// given configuration property
+    pub fn seed_from_config(&mut self, key: &str) -> &mut Self {
+        self.mode = RandomMode::Seeded(SeededRandom {
+            seed: 0,
+            seed_config_key: Some(key.to_string()),
+        });
+        self
+    }
+
+    /// Pass through the host random sources, appending all random data
+    /// returned to the guest to the log file at the given path under the
+    /// host preopens
+    ///
+    /// The log is the raw random bytes in the order they were returned,
+    /// with integers in little-endian byte order.
+    pub fn record(&mut self, path: &str) -> &mut Self {
+        self.mode = RandomMode::Record(RecordRandom {
+            path: path.to_string(),
+        });
+        self
+    }
+
+    /// Replay the random data of a log recorded with [`VirtRandom::record`],
+    /// trapping when the guest reads beyond the end of the log
+    pub fn replay(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.mode = RandomMode::Replay(ReplayRandom { path: path.into() });
+        self
+    }
+
+    /// Set the policy for the cryptographically-secure `wasi:random/random`,
+    /// overriding the mode
+    pub fn secure(&mut self, policy: RandomPolicy) -> &mut Self {
+        self.secure = Some(policy);
+        self
+    }
+
+    /// Set the policy for `wasi:random/insecure`, overriding the mode
+    pub fn insecure(&mut self, policy: RandomPolicy) -> &mut Self {
+        self.insecure = Some(policy);
+        self
+    }
+
+    /// Set the policy for `wasi:random/insecure-seed`, overriding the mode
+    pub fn insecure_seed(&mut self, policy: RandomPolicy) -> &mut Self {
+        self.insecure_seed = Some(policy);
+        self
+    }
+
+    /// The policies of the `wasi:random/random`, `insecure` and
+    /// `insecure-seed` interfaces, falling back to the mode
+    pub(crate) fn policies(&self) -> Result<[RandomPolicy; 3]> {
+        let default = match self.mode {
+            RandomMode::Allow => RandomPolicy::Allow,
+            RandomMode::Deny => RandomPolicy::Deny,
+            RandomMode::Seeded(_) => RandomPolicy::Seeded,
+            RandomMode::Record(_) => RandomPolicy::Record,
+            RandomMode::Replay(_) => RandomPolicy::Replay,
+        };
+        let policies = [self.secure, self.insecure, self.insecure_seed]
+            .map(|policy| policy.unwrap_or(default));
+        for (iface, policy) in RANDOM_INTERFACES.iter().zip(policies) {
+            match (policy, &self.mode) {
+                (RandomPolicy::Seeded, RandomMode::Seeded(_))
+                | (RandomPolicy::Record, RandomMode::Record(_))
+                | (RandomPolicy::Replay, RandomMode::Replay(_))
+                | (RandomPolicy::Allow | RandomPolicy::Deny, _) => {}
+                (RandomPolicy::Seeded, _) => {
+                    bail!("seeded wasi:random/{iface} requires a seed from the seeded random mode")
+                }
+                (RandomPolicy::Record, _) => {
+                    bail!("recording wasi:random/{iface} requires a log file from the record random mode")
+                }
+                (RandomPolicy::Replay, _) => {
+                    bail!("replaying wasi:random/{iface} requires a log file from the replay random mode")
+                }
+            }
+        }
+        Ok(policies)
+    }
+
+    /// The configuration property the seed is read from at runtime, if any
+    /// interface is seeded
+    pub(crate) fn seed_config_key(&self) -> Option<&str> {
+        let any_seeded = self
+            .policies()
+            .is_ok_and(|policies| policies.contains(&RandomPolicy::Seeded));
+        match &self.mode {
+            RandomMode::Seeded(seeded) if any_seeded => seeded.seed_config_key.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether any interface records its random data to a log file under
+    /// the host preopens
+    pub(crate) fn records(&self) -> bool {
+        self.policies()
+            .is_ok_and(|policies| policies.contains(&RandomPolicy::Record))
+    }
+}
+
+/// Random may also be configured as a boolean, allowing or denying the host random sources
+pub(crate) fn deserialize_random<'de, D>(deserializer: D) -> Result<Option<VirtRandom>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RandomCfg {
+        Enabled(bool),
+        Virt(VirtRandom),
+    }
+    Ok(Some(match RandomCfg::deserialize(deserializer)? {
+        RandomCfg::Enabled(true) => VirtRandom::default(),
+        RandomCfg::Enabled(false) => VirtRandom {
+            mode: RandomMode::Deny,
+            ..Default::default()
+        },
+        RandomCfg::Virt(random) => random,
+    }))
+}
+
+pub(crate) fn create_random_virt(module: &mut Module, random: &VirtRandom) -> Result<()> {
+    let random_ptr_addr = {
+        let random_ptr_export = module
+            .exports
+            .iter()
+            .find(|expt| expt.name.as_str() == "random")
+            .context("Adapter 'random' is not exported")?;
+        let ExportItem::Global(random_ptr_global) = random_ptr_export.item else {
+            bail!("Adapter 'random' not a global");
+        };
+        let GlobalKind::Local(ConstExpr::Value(Value::I32(random_ptr_addr))) =
+            &module.globals.get(random_ptr_global).kind
+        else {
+            bail!("Adapter 'random' not a local I32 global value");
+        };
+        *random_ptr_addr as u32
+    };
+
+    let policies = random.policies()?;
+    if policies
+        .iter()
+        .all(|policy| matches!(policy, RandomPolicy::Allow | RandomPolicy::Deny))
+    {
+        return Ok(());
+    }
+
+    // seeded and replayed interfaces never read the host random source
+    for (iface, policy) in RANDOM_INTERFACES.iter().zip(policies) {
+        if matches!(policy, RandomPolicy::Seeded | RandomPolicy::Replay) {
+            stub_virt(module, &[&format!("wasi:random/{iface}@")], false)?;
+        }
+    }
+
+    let memory = module.get_memory_id()?;
+
+    // the seed, the config key of the seed, the record path and the replay log
+    let mut seed = 0;
+    let mut seed_key = None;
+    let mut record_path = None;
+    let mut replay_data = None;
+    match &random.mode {
+        RandomMode::Allow | RandomMode::Deny => {}
+        RandomMode::Seeded(seeded) => {
+            seed = seeded.seed;
+            if let Some(key) = &seeded.seed_config_key {
+                if key.is_empty() {
+                    bail!("the random seed config key must not be empty");
+                }
+                seed_key = Some((key.len(), add_stack_data(module, memory, key.as_bytes())?));
+            }
+        }
+        RandomMode::Record(record) => {
+            if record.path.is_empty() {
+                bail!("the random record path must not be empty");
+            }
+            record_path = Some((
+                record.path.len(),
+                add_stack_data(module, memory, record.path.as_bytes())?,
+            ));
+        }
+        RandomMode::Replay(replay) => {
+            let log = fs::read(&replay.path).with_context(|| {
+                format!(
+                    "failed to read random replay log [{}]",
+                    replay.path.display()
+                )
+            })?;
+            if u32::try_from(log.len()).is_err() {
+                bail!(
+                    "random replay log [{}] is larger than the maximum of {} bytes",
+                    replay.path.display(),
+                    u32::MAX
+                );
+            }
+            replay_data = Some((log.len(), add_stack_data(module, memory, &log)?));
+        }
+    }
+
+    // In the existing static data segment, update the static data options.
+    //
+    // From virtual-adapter/src/random.rs:
+    //
+    // #[repr(C)]
+    // pub struct Random {
+    //     /// The `wasi:random/random` mode
+    //     /// [byte 0]
+    //     secure_mode: u8,
+    //     /// The `wasi:random/insecure` mode
+    //     /// [byte 1]
+    //     insecure_mode: u8,
+    //     /// The `wasi:random/insecure-seed` mode
+    //     /// [byte 2]
+    //     insecure_seed_mode: u8,
+    //     /// The seed of the generator, when not read from the config
+    //     /// [byte 8]
+    //     seed: u64,
+    //     /// The byte length of the config key to read the seed from at runtime,
+    //     /// with zero using the seed above
+    //     /// [byte 16]
+    //     seed_key_len: u32,
+    //     /// The config key bytes
+    //     /// [byte 20]
+    //     seed_key: *const u8,
+    //     /// The byte length of the path of the log file to record to, under the host preopens
+    //     /// [byte 24]
+    //     record_path_len: u32,
+    //     /// The record path bytes
+    //     /// [byte 28]
+    //     record_path: *const u8,
+    //     /// The byte length of the embedded log to replay
+    //     /// [byte 32]
+    //     replay_len: u32,
+    //     /// The embedded log bytes
+    //     /// [byte 36]
+    //     replay_data: *const u8,
+    // }
+    let (data, data_offset) = get_active_data_segment(module, memory, random_ptr_addr)?;
+
+    // the zero-initialized static may lie beyond the end of the data segment
+    const RANDOM_STATIC_LEN: usize = 40;
+    if data.value.len() < data_offset + RANDOM_STATIC_LEN {
+        data.value.resize(data_offset + RANDOM_STATIC_LEN, 0);
+    }
+
+    let bytes = data.value.as_mut_slice();
+
+    for (idx, policy) in policies.into_iter().enumerate() {
+        bytes[data_offset + idx] = match policy {
+            RandomPolicy::Seeded => RANDOM_MODE_SEEDED,
+            RandomPolicy::Record => RANDOM_MODE_RECORD,
+            RandomPolicy::Replay => RANDOM_MODE_REPLAY,
+            RandomPolicy::Allow | RandomPolicy::Deny => RANDOM_MODE_HOST,
+        };
+    }
+    bytes[data_offset + 8..data_offset + 16].copy_from_slice(&seed.to_le_bytes());
+    for (field_offset, field) in [(16, seed_key), (24, record_path), (32, replay_data)] {
+        if let Some((len, addr)) = field {
+            bytes[data_offset + field_offset..data_offset + field_offset + 4]
+                .copy_from_slice(&(len as u32).to_le_bytes());
+            bytes[data_offset + field_offset + 4..data_offset + field_offset + 8]
+                .copy_from_slice(&addr.to_le_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+/// Add the bytes as a new data segment at the top of the stack, returning their address
+fn add_stack_data(module: &mut Module, memory: MemoryId, bytes: &[u8]) -> Result<u32> {
+    // the stack global is bumped by 8 byte alignment
+    let mut bytes = bytes.to_vec();
+    let rem = bytes.len() % 8;
+    if rem > 0 {
+        bytes.resize(bytes.len() + 8 - rem, 0);
+    }
+    // Offset the stack global by the data length
+    let addr = bump_stack_global(module, bytes.len() as i32)?;
+    // Add a new data segment for this new range created at the top of the stack
+    module.data.add(
+        DataKind::Active {
+            memory,
+            offset: ConstExpr::Value(Value::I32(addr as i32)),
+        },
+        bytes,
+    );
+    Ok(addr)
+}
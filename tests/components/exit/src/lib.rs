@@ -0,0 +1,54 @@
+wit_bindgen::generate!({
+    path: "../../../wit/0_2_1",
+    world: "virt-test",
+    generate_all
+});
+
+struct VirtTestComponent;
+
+impl Guest for VirtTestComponent {
+    fn test_get_env() -> Vec<(String, String)> {
+        unimplemented!();
+    }
+    fn test_get_args() -> Vec<String> {
+        unimplemented!();
+    }
+    fn test_get_cwd() -> Option<String> {
+        unimplemented!();
+    }
+    fn test_get_clocks() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_clocks_nanos() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_clock_resolutions() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_sleep(_duration: u64) -> u64 {
+        unimplemented!();
+    }
+    fn test_get_random() -> (u64, u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_config() -> Vec<(String, String)> {
+        unimplemented!();
+    }
+    fn test_file_read(_path: String) -> String {
+        unimplemented!();
+    }
+    fn test_stdio() -> () {
+        unimplemented!();
+    }
+    fn test_take_stdout() -> Vec<u8> {
+        unimplemented!();
+    }
+    fn test_exit(success: bool) {
+        wasi::cli::exit::exit(if success { Ok(()) } else { Err(()) });
+    }
+    fn test_exit_status() -> Option<u8> {
+        local::virt::exit_status::exit_status()
+    }
+}
+
+export!(VirtTestComponent);
@@ -13,6 +13,27 @@ impl Guest for VirtTestComponent {
     fn test_get_env() -> Vec<(String, String)> {
         Vec::new()
     }
+    fn test_get_args() -> Vec<String> {
+        Vec::new()
+    }
+    fn test_get_cwd() -> Option<String> {
+        wasi::cli::environment::initial_cwd()
+    }
+    fn test_get_clocks() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_clocks_nanos() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_clock_resolutions() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_sleep(_duration: u64) -> u64 {
+        unimplemented!();
+    }
+    fn test_get_random() -> (u64, u64, u64) {
+        unimplemented!();
+    }
     fn test_get_config() -> Vec<(String, String)> {
         Vec::new()
     }
@@ -68,6 +89,15 @@ impl Guest for VirtTestComponent {
     fn test_stdio() -> () {
         unimplemented!();
     }
+    fn test_take_stdout() -> Vec<u8> {
+        unimplemented!();
+    }
+    fn test_exit(_success: bool) {
+        unimplemented!();
+    }
+    fn test_exit_status() -> Option<u8> {
+        unimplemented!();
+    }
 }
 
 export!(VirtTestComponent);
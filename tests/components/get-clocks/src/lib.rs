@@ -0,0 +1,67 @@
+wit_bindgen::generate!({
+    path: "../../../wit/0_2_1",
+    world: "virt-test",
+    generate_all
+});
+
+struct VirtTestComponent;
+
+impl Guest for VirtTestComponent {
+    fn test_get_env() -> Vec<(String, String)> {
+        unimplemented!();
+    }
+    fn test_get_args() -> Vec<String> {
+        unimplemented!();
+    }
+    fn test_get_cwd() -> Option<String> {
+        unimplemented!();
+    }
+    fn test_get_clocks() -> (u64, u64) {
+        (
+            wasi::clocks::wall_clock::now().seconds,
+            wasi::clocks::monotonic_clock::now(),
+        )
+    }
+    fn test_get_clocks_nanos() -> (u64, u64) {
+        let wall = wasi::clocks::wall_clock::now();
+        (
+            wall.seconds * 1_000_000_000 + wall.nanoseconds as u64,
+            wasi::clocks::monotonic_clock::now(),
+        )
+    }
+    fn test_get_clock_resolutions() -> (u64, u64) {
+        let wall = wasi::clocks::wall_clock::resolution();
+        (
+            wall.seconds * 1_000_000_000 + wall.nanoseconds as u64,
+            wasi::clocks::monotonic_clock::resolution(),
+        )
+    }
+    fn test_sleep(duration: u64) -> u64 {
+        let timer = wasi::clocks::monotonic_clock::subscribe_duration(duration);
+        wasi::io::poll::poll(&[&timer]);
+        wasi::clocks::monotonic_clock::now()
+    }
+    fn test_get_random() -> (u64, u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_config() -> Vec<(String, String)> {
+        unimplemented!();
+    }
+    fn test_file_read(_path: String) -> String {
+        unimplemented!();
+    }
+    fn test_stdio() -> () {
+        unimplemented!();
+    }
+    fn test_take_stdout() -> Vec<u8> {
+        unimplemented!();
+    }
+    fn test_exit(_success: bool) {
+        unimplemented!();
+    }
+    fn test_exit_status() -> Option<u8> {
+        unimplemented!();
+    }
+}
+
+export!(VirtTestComponent);
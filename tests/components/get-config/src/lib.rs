@@ -10,8 +10,29 @@ impl Guest for VirtTestComponent {
     fn test_get_env() -> Vec<(String, String)> {
         unimplemented!();
     }
+    fn test_get_args() -> Vec<String> {
+        unimplemented!();
+    }
+    fn test_get_cwd() -> Option<String> {
+        unimplemented!();
+    }
+    fn test_get_clocks() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_clocks_nanos() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_clock_resolutions() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_sleep(_duration: u64) -> u64 {
+        unimplemented!();
+    }
+    fn test_get_random() -> (u64, u64, u64) {
+        unimplemented!();
+    }
     fn test_get_config() -> Vec<(String, String)> {
-        wasi::config::store::get_all().unwrap()
+        wasi::config0_2_0_draft::store::get_all().unwrap()
     }
     fn test_file_read(_path: String) -> String {
         unimplemented!();
@@ -19,6 +40,15 @@ impl Guest for VirtTestComponent {
     fn test_stdio() -> () {
         unimplemented!();
     }
+    fn test_take_stdout() -> Vec<u8> {
+        unimplemented!();
+    }
+    fn test_exit(_success: bool) {
+        unimplemented!();
+    }
+    fn test_exit_status() -> Option<u8> {
+        unimplemented!();
+    }
 }
 
 export!(VirtTestComponent);
@@ -10,6 +10,27 @@ impl Guest for VirtTestComponent {
     fn test_get_env() -> Vec<(String, String)> {
         unimplemented!();
     }
+    fn test_get_args() -> Vec<String> {
+        unimplemented!();
+    }
+    fn test_get_cwd() -> Option<String> {
+        unimplemented!();
+    }
+    fn test_get_clocks() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_clocks_nanos() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_get_clock_resolutions() -> (u64, u64) {
+        unimplemented!();
+    }
+    fn test_sleep(_duration: u64) -> u64 {
+        unimplemented!();
+    }
+    fn test_get_random() -> (u64, u64, u64) {
+        unimplemented!();
+    }
     fn test_get_config() -> Vec<(String, String)> {
         unimplemented!();
     }
@@ -19,6 +40,15 @@ impl Guest for VirtTestComponent {
     fn test_stdio() -> () {
         println!("Hello world");
     }
+    fn test_take_stdout() -> Vec<u8> {
+        local::virt::captured_output::take_stdout()
+    }
+    fn test_exit(_success: bool) {
+        unimplemented!();
+    }
+    fn test_exit_status() -> Option<u8> {
+        unimplemented!();
+    }
 }
 
 export!(VirtTestComponent);
@@ -7,15 +7,16 @@ use anyhow::{anyhow, bail, Context, Result};
 use heck::ToSnakeCase;
 use log::debug;
 use serde::Deserialize;
-use wasi_virt::WasiVirt;
+use wasi_virt::{ExitMode, WasiVirt};
 use wasm_compose::composer::ComponentComposer;
 use wasmparser::{Chunk, Parser, Payload};
-use wasmtime::component::ResourceTable;
+use wasmtime::component::{ComponentType, Lift, Lower, ResourceTable};
 use wasmtime::{
     component::{Component, Linker},
     Config, Engine, Store, WasmBacktraceDetails,
 };
-use wasmtime_wasi::{DirPerms, FilePerms, IoView, WasiCtx, WasiCtxBuilder, WasiView};
+use wasmtime_wasi::pipe::MemoryOutputPipe;
+use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, IoView, WasiCtx, WasiCtxBuilder, WasiView};
 use wasmtime_wasi_config::{WasiConfig, WasiConfigVariables};
 use wit_component::{ComponentEncoder, DecodedWasm};
 use wit_parser::WorldItem;
@@ -26,6 +27,20 @@ wasmtime::component::bindgen!({
     async: true
 });
 
+/// The `wasi:config/store@0.2.0-rc.1` error variant
+#[derive(ComponentType, Lift, Lower)]
+#[component(variant)]
+enum ConfigErrorRc1 {
+    #[component(name = "upstream")]
+    #[allow(dead_code)]
+    Upstream(String),
+    #[component(name = "io")]
+    #[allow(dead_code)]
+    Io(String),
+}
+
+type ConfigResultRc1<T> = Result<(Result<T, ConfigErrorRc1>,)>;
+
 fn cmd(arg: &str) -> Result<()> {
     let mut cmd = if cfg!(target_os = "windows") {
         let mut cmd = Command::new("cmd");
@@ -51,11 +66,49 @@ fn cmd(arg: &str) -> Result<()> {
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 struct TestExpectation {
     env: Option<Vec<(String, String)>>,
+    args: Option<Vec<String>>,
+    cwd: Option<String>,
     config: Option<Vec<(String, String)>>,
     file_read: Option<String>,
+    /// The wall-clock seconds and monotonic clock instant
+    clocks: Option<(u64, u64)>,
+    /// Lower bounds for the wall-clock seconds and monotonic clock instant
+    clocks_min: Option<(u64, u64)>,
+    /// The sleep duration and the monotonic clock instant after sleeping
+    sleep: Option<(u64, u64)>,
+    /// The sleep duration and a lower bound for the monotonic clock instant after sleeping
+    sleep_min: Option<(u64, u64)>,
+    /// The granularity in nanoseconds which both clocks must report as their resolution and
+    /// read non-zero multiples of, after the sleep expectations
+    clocks_granularity: Option<u64>,
+    /// A secure random u64, an insecure random u64 and the first insecure seed value
+    random: Option<(u64, u64, u64)>,
+    /// The insecure random u64 and the first insecure seed value, ignoring the secure random u64
+    random_insecure: Option<(u64, u64)>,
+    /// The log file under tests/generated recording the random data, checked against the random values
+    random_recorded: Option<String>,
+    /// Call exit with this success value, expecting the call to return
+    exit: Option<bool>,
+    /// Call exit with failure, expecting the host instance to exit with this code
+    exit_code: Option<i32>,
+    /// Call exit with failure, expecting no recorded status before the call and this status after
+    exit_status: Option<u8>,
     encapsulation: Option<bool>,
     stdout: Option<String>,
+    /// The stdout captured by the virtualization after the stdio test call
+    captured_stdout: Option<String>,
     imports: Option<TestExpectationImports>,
+    /// Expect the virtualization to fail with an error containing this message
+    virt_error: Option<String>,
+    /// Interfaces which the virtualization must export
+    virt_exports: Option<Vec<String>>,
+    /// Expect the given test call ("env", "config", "random", "exit", failing, or "sleep", for
+    /// an hour) to trap
+    trap: Option<String>,
+    /// Text the host stderr must contain after all test calls
+    stderr: Option<String>,
+    /// Strings which must not appear in the virtualization
+    hidden: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -71,9 +124,18 @@ struct TestCase {
     component: String,
     compose: Option<bool>,
     host_env: Option<BTreeMap<String, String>>,
+    host_args: Option<Vec<String>>,
     host_config: Option<BTreeMap<String, String>>,
     host_fs_path: Option<String>,
+    /// Preopen tests/generated read-write at /generated
+    host_generated_dir: Option<bool>,
     virt_opts: Option<WasiVirt>,
+    /// Override files loaded into the env virtualization
+    env_files: Option<Vec<String>>,
+    /// Override files loaded into the config virtualization
+    config_files: Option<Vec<String>>,
+    /// The exit mode, with the host exit denied by default
+    exit_mode: Option<ExitMode>,
     expect: TestExpectation,
 }
 
@@ -142,7 +204,19 @@ async fn virt_test() -> Result<()> {
         let mut virt_component_path = generated_path.join(test_case_name);
         virt_component_path.set_extension("wasm");
         let mut virt_opts = test.virt_opts.clone().unwrap_or_default();
-        virt_opts.exit(Default::default());
+        // errors loading the override files are checked as virtualization errors
+        let overrides_result = (|| -> Result<()> {
+            for env_file in test.env_files.iter().flatten() {
+                virt_opts.env().overrides_file(env_file)?;
+            }
+            for config_file in test.config_files.iter().flatten() {
+                virt_opts.config().overrides_file(config_file)?;
+            }
+            Ok(())
+        })();
+        virt_opts
+            .exit(Default::default())
+            .mode(test.exit_mode.unwrap_or(ExitMode::Deny));
         if debug_enabled {
             virt_opts.debug(true);
             if test_case_name != "encapsulate" {
@@ -164,7 +238,17 @@ async fn virt_test() -> Result<()> {
         // TODO: move to 0.2.3 in tests
         virt_opts.wasi_version(semver::Version::new(0, 2, 1));
 
-        let virt_component = virt_opts.finish().with_context(|| {
+        let virt_result = overrides_result.and_then(|()| virt_opts.finish());
+        if let Some(expect_virt_error) = &test.expect.virt_error {
+            match virt_result {
+                Ok(_) => bail!("Expected virtualization error testing [{test_case_path:?}]"),
+                Err(err) if !format!("{err:#}").contains(expect_virt_error) => {
+                    bail!("Unexpected virtualization error testing [{test_case_path:?}]: {err:#}")
+                }
+                Err(_) => continue,
+            }
+        }
+        let virt_component = virt_result.with_context(|| {
             format!(
                 "Error creating virtual adapter {:?} for {:?}",
                 test_case_path, component_core_path
@@ -173,6 +257,27 @@ async fn virt_test() -> Result<()> {
 
         fs::write(&virt_component_path, &virt_component.adapter)?;
 
+        // verify no cleartext secrets
+        for hidden in test.expect.hidden.iter().flatten() {
+            if virt_component
+                .adapter
+                .windows(hidden.len())
+                .any(|window| window == hidden.as_bytes())
+            {
+                bail!("Unexpected \"{hidden}\" in virtualization {virt_component_path:?}");
+            }
+        }
+
+        // verify the virtualization exports
+        if let Some(expect_exports) = &test.expect.virt_exports {
+            let virt_exports = collect_component_exports(&virt_component.adapter)?;
+            for expect_export in expect_exports {
+                if !virt_exports.iter().any(|e| e.starts_with(expect_export)) {
+                    bail!("Missing export \"{expect_export}\" in virtualization {virt_component_path:?}");
+                }
+            }
+        }
+
         // verify the encapsulation
         if test.expect.encapsulation.unwrap_or(false) {
             if let Some(impt) = has_component_import(virt_component.adapter.as_slice())? {
@@ -215,11 +320,26 @@ async fn virt_test() -> Result<()> {
         let _ = builder
             .inherit_stdio()
             .preopened_dir(".", "/", DirPerms::READ, FilePerms::READ);
+        let stderr = MemoryOutputPipe::new(64 * 1024);
+        if test.expect.stderr.is_some() {
+            builder.stderr(stderr.clone());
+        }
+        if test.host_generated_dir.unwrap_or_default() {
+            builder.preopened_dir(
+                &generated_path,
+                "/generated",
+                DirPerms::all(),
+                FilePerms::all(),
+            )?;
+        }
         if let Some(host_env) = &test.host_env {
             for (k, v) in host_env {
                 builder.env(k, v);
             }
         }
+        if let Some(host_args) = &test.host_args {
+            builder.args(host_args);
+        }
         let wasi_config = {
             let mut config = WasiConfigVariables::new();
             if let Some(host_config) = &test.host_config {
@@ -247,6 +367,7 @@ async fn virt_test() -> Result<()> {
             table: ResourceTable,
             wasi: WasiCtx,
             wasi_config: WasiConfigVariables,
+            host_config: BTreeMap<String, String>,
         }
         impl IoView for CommandCtx {
             fn table(&mut self) -> &mut ResourceTable {
@@ -268,12 +389,41 @@ async fn virt_test() -> Result<()> {
         wasmtime_wasi_config::add_to_linker(&mut linker, |ctx: &mut CommandCtx| {
             WasiConfig::new(ctx.wasi_config())
         })?;
+        let mut config_rc1 = linker.instance("wasi:config/store@0.2.0-rc.1")?;
+        config_rc1.func_wrap(
+            "get",
+            |ctx, (key,): (String,)| -> ConfigResultRc1<Option<String>> {
+                let ctx: &CommandCtx = ctx.data();
+                Ok((Ok(ctx.host_config.get(&key).cloned()),))
+            },
+        )?;
+        config_rc1.func_wrap(
+            "get-all",
+            |ctx, (): ()| -> ConfigResultRc1<Vec<(String, String)>> {
+                let ctx: &CommandCtx = ctx.data();
+                Ok((Ok(ctx.host_config.clone().into_iter().collect()),))
+            },
+        )?;
+        // the virtualization accessors are only provided by the composition when virtualized
+        linker.instance("local:virt/captured-output")?.func_wrap(
+            "take-stdout",
+            |_, (): ()| -> Result<(Vec<u8>,)> {
+                bail!("stdout is not captured by the virtualization")
+            },
+        )?;
+        linker.instance("local:virt/exit-status")?.func_wrap(
+            "exit-status",
+            |_, (): ()| -> Result<(Option<u8>,)> {
+                bail!("the exit status is not recorded by the virtualization")
+            },
+        )?;
         let mut store = Store::new(
             &engine,
             CommandCtx {
                 table,
                 wasi,
                 wasi_config,
+                host_config: test.host_config.clone().unwrap_or_default(),
             },
         );
 
@@ -300,6 +450,44 @@ async fn virt_test() -> Result<()> {
             }
         }
 
+        // arguments expectation check
+        if let Some(expect_args) = &test.expect.args {
+            let args = instance.call_test_get_args(&mut store).await?;
+            if !args.eq(expect_args) {
+                return Err(anyhow!(
+                    "Unexpected arguments testing {:?}:
+
+    \x1b[1mExpected:\x1b[0m {:?}
+    \x1b[1mActual:\x1b[0m {:?}
+
+    {:?}",
+                    test_case_path,
+                    expect_args,
+                    args,
+                    test
+                ));
+            }
+        }
+
+        // working directory expectation check
+        if let Some(expect_cwd) = &test.expect.cwd {
+            let cwd = instance.call_test_get_cwd(&mut store).await?;
+            if cwd.as_ref() != Some(expect_cwd) {
+                return Err(anyhow!(
+                    "Unexpected working directory testing {:?}:
+
+    \x1b[1mExpected:\x1b[0m {:?}
+    \x1b[1mActual:\x1b[0m {:?}
+
+    {:?}",
+                    test_case_path,
+                    expect_cwd,
+                    cwd,
+                    test
+                ));
+            }
+        }
+
         // config property expectation check
         if let Some(expect_config) = &test.expect.config {
             let config_props = instance.call_test_get_config(&mut store).await?;
@@ -334,11 +522,181 @@ async fn virt_test() -> Result<()> {
             }
         }
 
+        // clocks expectation check
+        if let Some(expect_clocks) = &test.expect.clocks {
+            let clocks = instance.call_test_get_clocks(&mut store).await?;
+            if !clocks.eq(expect_clocks) {
+                bail!(
+                    "Unexpected clocks testing [{test_case_path:?}]: expected {expect_clocks:?}, got {clocks:?}"
+                );
+            }
+        }
+
+        if let Some((min_wall, min_monotonic)) = test.expect.clocks_min {
+            let (wall, monotonic) = instance.call_test_get_clocks(&mut store).await?;
+            if wall < min_wall || monotonic < min_monotonic {
+                bail!(
+                    "Unexpected clocks testing [{test_case_path:?}]: expected at least {:?}, got {:?}",
+                    (min_wall, min_monotonic),
+                    (wall, monotonic)
+                );
+            }
+        }
+
+        // sleep expectation check, after the clocks expectation check
+        if let Some((duration, expect_now)) = test.expect.sleep {
+            let now = instance.call_test_sleep(&mut store, duration).await?;
+            if now != expect_now {
+                bail!(
+                    "Unexpected clock after sleeping testing [{test_case_path:?}]: expected {expect_now}, got {now}"
+                );
+            }
+        }
+        if let Some((duration, min_now)) = test.expect.sleep_min {
+            let now = instance.call_test_sleep(&mut store, duration).await?;
+            if now < min_now {
+                bail!(
+                    "Unexpected clock after sleeping testing [{test_case_path:?}]: expected at least {min_now}, got {now}"
+                );
+            }
+        }
+
+        if let Some(granularity) = test.expect.clocks_granularity {
+            let resolutions = instance.call_test_get_clock_resolutions(&mut store).await?;
+            if resolutions != (granularity, granularity) {
+                bail!(
+                    "Unexpected clock resolutions testing [{test_case_path:?}]: expected {granularity}, got {resolutions:?}"
+                );
+            }
+            let (wall, monotonic) = instance.call_test_get_clocks_nanos(&mut store).await?;
+            for reading in [wall, monotonic] {
+                if reading == 0 || reading % granularity != 0 {
+                    bail!(
+                        "Unexpected clocks testing [{test_case_path:?}]: expected non-zero multiples of {granularity}, got {:?}",
+                        (wall, monotonic)
+                    );
+                }
+            }
+        }
+
+        // random expectation check
+        if let Some(expect_random) = &test.expect.random {
+            let random = instance.call_test_get_random(&mut store).await?;
+            if !random.eq(expect_random) {
+                bail!(
+                    "Unexpected random testing [{test_case_path:?}]: expected {expect_random:?}, got {random:?}"
+                );
+            }
+        }
+        if let Some(expect_random) = test.expect.random_insecure {
+            let (_, insecure, insecure_seed) = instance.call_test_get_random(&mut store).await?;
+            if (insecure, insecure_seed) != expect_random {
+                bail!(
+                    "Unexpected insecure random testing [{test_case_path:?}]: expected {expect_random:?}, got {:?}",
+                    (insecure, insecure_seed)
+                );
+            }
+        }
+
+        if let Some(log_name) = &test.expect.random_recorded {
+            let log_path = generated_path.join(log_name);
+            // a log left over from a previous run is replaced, not appended to
+            fs::write(&log_path, b"stale random log")?;
+            let (secure, insecure, insecure_seed) =
+                instance.call_test_get_random(&mut store).await?;
+            let log = fs::read(&log_path)
+                .with_context(|| format!("failed to read random log [{log_path:?}]"))?;
+            // the insecure seed is a pair, of which only the first value is returned
+            let expect_log = [secure, insecure, insecure_seed]
+                .map(u64::to_le_bytes)
+                .concat();
+            if log.len() != 32 || log[..24] != expect_log {
+                bail!(
+                    "Unexpected random log testing [{test_case_path:?}]: expected {expect_log:?} and a further 8 bytes, got {log:?}"
+                );
+            }
+        }
+
+        if let Some(success) = test.expect.exit {
+            instance
+                .call_test_exit(&mut store, success)
+                .await
+                .with_context(|| {
+                    format!("Expected exit call to return testing [{test_case_path:?}]")
+                })?;
+        }
+
+        if let Some(expect_status) = test.expect.exit_status {
+            let status = instance.call_test_exit_status(&mut store).await?;
+            if status.is_some() {
+                bail!("Unexpected exit status {status:?} before exit testing [{test_case_path:?}]");
+            }
+            instance.call_test_exit(&mut store, false).await?;
+            let status = instance.call_test_exit_status(&mut store).await?;
+            if status != Some(expect_status) {
+                bail!(
+                    "Unexpected exit status testing [{test_case_path:?}]: expected {expect_status}, got {status:?}"
+                );
+            }
+        }
+
+        // exit code expectation check, last as the instance cannot be reused
+        if let Some(expect_code) = test.expect.exit_code {
+            let err = match instance.call_test_exit(&mut store, false).await {
+                Ok(()) => bail!("Expected exit call to exit testing [{test_case_path:?}]"),
+                Err(err) => err,
+            };
+            match err.downcast_ref::<I32Exit>() {
+                Some(I32Exit(code)) if *code == expect_code => {}
+                _ => bail!(
+                    "Unexpected exit testing [{test_case_path:?}]: expected code {expect_code}, got {err:?}"
+                ),
+            }
+        }
+
         if let Some(_expect_stdout) = &test.expect.stdout {
             // todo: expectation pending wasmtime stream flushing
             instance.call_test_stdio(&mut store).await?;
         }
 
+        if let Some(expect_stdout) = &test.expect.captured_stdout {
+            instance.call_test_stdio(&mut store).await?;
+            let stdout = instance.call_test_take_stdout(&mut store).await?;
+            if stdout != expect_stdout.as_bytes() {
+                bail!(
+                    "Unexpected captured stdout testing [{test_case_path:?}]: expected {expect_stdout:?}, got {:?}",
+                    String::from_utf8_lossy(&stdout)
+                );
+            }
+        }
+
+        // trap expectation check, last as the instance cannot be reused
+        if let Some(expect_trap) = &test.expect.trap {
+            let result = match expect_trap.as_str() {
+                "env" => instance.call_test_get_env(&mut store).await.map(|_| ()),
+                "config" => instance.call_test_get_config(&mut store).await.map(|_| ()),
+                "random" => instance.call_test_get_random(&mut store).await.map(|_| ()),
+                "exit" => instance.call_test_exit(&mut store, false).await,
+                "sleep" => instance
+                    .call_test_sleep(&mut store, 3_600_000_000_000)
+                    .await
+                    .map(|_| ()),
+                _ => bail!("Unknown trap expectation [{expect_trap}] in [{test_case_path:?}]"),
+            };
+            if result.is_ok() {
+                bail!("Expected {expect_trap} call to trap testing [{test_case_path:?}]");
+            }
+        }
+
+        if let Some(expect_stderr) = &test.expect.stderr {
+            let stderr = String::from_utf8_lossy(&stderr.contents()).into_owned();
+            if !stderr.contains(expect_stderr.as_str()) {
+                bail!(
+                    "Expected stderr to contain {expect_stderr:?} testing [{test_case_path:?}], got {stderr:?}"
+                );
+            }
+        }
+
         if let Some(expect_imports) = &test.expect.imports {
             let component_imports = collect_component_imports(component_bytes)?;
 
@@ -375,6 +733,38 @@ async fn virt_test() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn clocks_jitter_reproducible() -> Result<()> {
+    let virtualize = |seed: Option<u64>| -> Result<Vec<u8>> {
+        let mut virt_opts = WasiVirt::default();
+        let clocks = virt_opts.clocks(true).coarsen(1_000_000, true);
+        if let Some(seed) = seed {
+            clocks.jitter_seed(seed);
+        }
+        virt_opts.wasm_opt(false);
+        Ok(virt_opts.finish()?.adapter)
+    };
+    if virtualize(None)? == virtualize(None)? {
+        bail!("Expected the jitter to be seeded differently without a jitter seed");
+    }
+    if virtualize(Some(7))? != virtualize(Some(7))? {
+        bail!("Expected the same jitter seed to produce the same virtualization");
+    }
+    if virtualize(Some(7))? == virtualize(Some(8))? {
+        bail!("Expected different jitter seeds to produce different virtualizations");
+    }
+    let mut virt_opts = WasiVirt::default();
+    virt_opts
+        .clocks(true)
+        .jitter_seed(7)
+        .coarsen(1_000_000, true);
+    virt_opts.wasm_opt(false);
+    if virt_opts.finish()?.adapter != virtualize(Some(7))? {
+        bail!("Expected the jitter seed to apply when set before coarsening the clocks");
+    }
+    Ok(())
+}
+
 fn has_component_import(bytes: &[u8]) -> Result<Option<String>> {
     let mut parser = Parser::new(0);
     let mut offset = 0;
@@ -439,3 +829,23 @@ fn collect_component_imports(component_bytes: Vec<u8>) -> Result<Vec<String>> {
 
     Ok(import_ids)
 }
+
+fn collect_component_exports(component_bytes: &[u8]) -> Result<Vec<String>> {
+    let (resolve, world_id) = match wit_component::decode(component_bytes)? {
+        DecodedWasm::WitPackage(..) => {
+            bail!("expected a component, found a WIT package")
+        }
+        DecodedWasm::Component(resolve, world_id) => (resolve, world_id),
+    };
+
+    let mut export_ids: Vec<String> = vec![];
+    for (_, export) in &resolve.worlds[world_id].exports {
+        if let WorldItem::Interface { id, .. } = export {
+            if let Some(id) = resolve.id_of(*id) {
+                export_ids.push(id);
+            }
+        }
+    }
+
+    Ok(export_ids)
+}
@@ -0,0 +1,309 @@
+use crate::bindings::exports::wasi::clocks::monotonic_clock::Guest as MonotonicClock;
+use crate::bindings::exports::wasi::clocks::wall_clock::{Datetime, Guest as WallClock};
+use crate::bindings::exports::wasi::io::poll::Pollable;
+use crate::bindings::wasi::clocks::{monotonic_clock, wall_clock};
+use crate::io::{datetime_map, fatal, log, IoPollable, DEBUG};
+use crate::{debug, VirtAdapter};
+
+/// Read the host clocks
+const MODE_HOST: u8 = 0;
+/// Report the fixed instants below
+const MODE_FROZEN: u8 = 1;
+/// Simulate the clocks from the instants below, advancing by the step on
+/// each monotonic clock reading and jumping forward to expiring timers
+const MODE_SIMULATED: u8 = 2;
+/// Read the host clocks, offsetting the wall clock and scaling the
+/// monotonic clock and its timers by the rate
+const MODE_ADJUSTED: u8 = 3;
+/// Read the host clocks, rounded down to the granularity
+const MODE_COARSENED: u8 = 4;
+
+/// Clamp waits beyond the sleep limit to the limit
+const SLEEP_LIMIT_CLAMP: u8 = 0;
+/// Resolve waits beyond the sleep limit immediately
+const SLEEP_LIMIT_READY: u8 = 1;
+/// Trap on waits beyond the sleep limit
+const SLEEP_LIMIT_TRAP: u8 = 2;
+
+#[repr(C)]
+pub struct Clocks {
+    /// The clocks mode
+    /// [byte 0]
+    mode: u8,
+    /// The frozen or starting wall-clock nanoseconds
+    /// [byte 4]
+    wall_nanoseconds: u32,
+    /// The frozen or starting wall-clock seconds since the Unix epoch
+    /// [byte 8]
+    wall_seconds: u64,
+    /// The frozen or starting monotonic clock instant in nanoseconds
+    /// [byte 16]
+    monotonic: u64,
+    /// The nanoseconds the simulated clocks advance on each monotonic clock reading
+    /// [byte 24]
+    step: u64,
+    /// How many times faster the adjusted monotonic clock runs than the host clock
+    /// [byte 32]
+    rate: f64,
+    /// The seconds added to the adjusted host wall clock
+    /// [byte 40]
+    wall_offset: i64,
+    /// The granularity in nanoseconds the coarsened host clocks are rounded down to
+    /// [byte 48]
+    granularity: u64,
+    /// The seed for the coarsened clocks jitter, with zero disabling jitter
+    /// [byte 56]
+    jitter_seed: u64,
+    /// The longest monotonic clock wait in nanoseconds permitted to the guest
+    /// [byte 64]
+    sleep_limit: u64,
+    /// The action for waits beyond the sleep limit
+    /// [byte 72]
+    sleep_limit_action: u8,
+}
+
+#[no_mangle]
+pub static mut clocks: Clocks = Clocks {
+    mode: MODE_HOST,
+    wall_nanoseconds: 0,
+    wall_seconds: 0,
+    monotonic: 0,
+    step: 0,
+    rate: 1.0,
+    wall_offset: 0,
+    granularity: 1,
+    jitter_seed: 0,
+    sleep_limit: u64::MAX,
+    sleep_limit_action: SLEEP_LIMIT_CLAMP,
+};
+
+/// The nanoseconds elapsed on the simulated clocks
+static mut ELAPSED: u64 = 0;
+
+/// The coarsened clocks jitter state
+static mut JITTER: u64 = 0;
+
+/// The last coarsened monotonic clock reading, as jitter may otherwise
+/// take the monotonic clock backwards
+static mut LAST_MONOTONIC: u64 = 0;
+
+fn mode() -> u8 {
+    unsafe { clocks.mode }
+}
+
+/// The current simulated monotonic clock instant
+fn simulated_now() -> u64 {
+    unsafe { clocks.monotonic.saturating_add(ELAPSED) }
+}
+
+/// Whether a simulated timer for the given instant has expired
+pub(crate) fn timer_ready(when: u64) -> bool {
+    simulated_now() >= when
+}
+
+/// Scale a host monotonic clock duration or instant to the adjusted clock
+fn to_adjusted(host: u64) -> u64 {
+    (host as f64 * unsafe { clocks.rate }) as u64
+}
+
+/// Scale an adjusted monotonic clock duration or instant to the host clock,
+/// rounding up so that timers never resolve early
+fn to_host(adjusted: u64) -> u64 {
+    (adjusted as f64 / unsafe { clocks.rate }).ceil() as u64
+}
+
+/// Round a host clock reading in nanoseconds down to the granularity,
+/// jittering the rounding when enabled
+fn coarsen(nanoseconds: u128) -> u128 {
+    let granularity = unsafe { clocks.granularity.max(1) } as u128;
+    let jitter = unsafe {
+        if clocks.jitter_seed == 0 {
+            0
+        } else {
+            if JITTER == 0 {
+                JITTER = clocks.jitter_seed;
+            }
+            // xorshift64
+            JITTER ^= JITTER << 13;
+            JITTER ^= JITTER >> 7;
+            JITTER ^= JITTER << 17;
+            JITTER as u128 % granularity
+        }
+    };
+    (nanoseconds + jitter) / granularity * granularity
+}
+
+/// The current monotonic clock instant, without advancing the simulated clocks
+fn instant_now() -> u64 {
+    match mode() {
+        MODE_FROZEN => unsafe { clocks.monotonic },
+        MODE_SIMULATED => simulated_now(),
+        MODE_ADJUSTED => to_adjusted(monotonic_clock::now()),
+        _ => monotonic_clock::now(),
+    }
+}
+
+fn duration_pollable(duration: u64) -> Pollable {
+    match mode() {
+        MODE_FROZEN => Pollable::new(IoPollable::Null),
+        MODE_SIMULATED => {
+            Pollable::new(IoPollable::Timer(simulated_now().saturating_add(duration)))
+        }
+        MODE_ADJUSTED => Pollable::new(IoPollable::Host(monotonic_clock::subscribe_duration(
+            to_host(duration),
+        ))),
+        _ => Pollable::new(IoPollable::Host(monotonic_clock::subscribe_duration(
+            duration,
+        ))),
+    }
+}
+
+/// The duration a wait is checked against the sleep limit with, which is the
+/// host time the wait takes when the clocks are adjusted
+fn limited_duration(duration: u64) -> u64 {
+    match mode() {
+        MODE_ADJUSTED => to_host(duration),
+        _ => duration,
+    }
+}
+
+/// Apply the sleep limit action to a wait beyond the sleep limit
+fn limited_pollable(duration: u64) -> Pollable {
+    let sleep_limit = unsafe { clocks.sleep_limit };
+    match unsafe { clocks.sleep_limit_action } {
+        SLEEP_LIMIT_READY => Pollable::new(IoPollable::Null),
+        SLEEP_LIMIT_TRAP => fatal(&format!(
+            "wasi:clocks/monotonic-clock wait of {duration}ns exceeds the {sleep_limit}ns sleep limit"
+        )),
+        // the limit is already in host time when the clocks are adjusted
+        _ if mode() == MODE_ADJUSTED => Pollable::new(IoPollable::Host(
+            monotonic_clock::subscribe_duration(sleep_limit),
+        )),
+        _ => duration_pollable(sleep_limit),
+    }
+}
+
+/// Jump the simulated clocks forward until the given instant
+pub(crate) fn advance_to(when: u64) {
+    unsafe {
+        ELAPSED = ELAPSED.max(when.saturating_sub(clocks.monotonic));
+    }
+}
+
+impl MonotonicClock for VirtAdapter {
+    fn now() -> u64 {
+        debug!("CALL wasi:clocks/monotonic-clock#now");
+        match mode() {
+            MODE_FROZEN => unsafe { clocks.monotonic },
+            MODE_SIMULATED => {
+                let now = simulated_now();
+                unsafe {
+                    ELAPSED = ELAPSED.saturating_add(clocks.step);
+                }
+                now
+            }
+            MODE_ADJUSTED => to_adjusted(monotonic_clock::now()),
+            MODE_COARSENED => {
+                let now = coarsen(monotonic_clock::now() as u128).min(u64::MAX as u128) as u64;
+                unsafe {
+                    LAST_MONOTONIC = LAST_MONOTONIC.max(now);
+                    LAST_MONOTONIC
+                }
+            }
+            _ => monotonic_clock::now(),
+        }
+    }
+    fn resolution() -> u64 {
+        debug!("CALL wasi:clocks/monotonic-clock#resolution");
+        match mode() {
+            MODE_FROZEN | MODE_SIMULATED => 1,
+            MODE_ADJUSTED => to_adjusted(monotonic_clock::resolution()).max(1),
+            MODE_COARSENED => unsafe { clocks.granularity }.max(monotonic_clock::resolution()),
+            _ => monotonic_clock::resolution(),
+        }
+    }
+    fn subscribe_instant(when: u64) -> Pollable {
+        debug!("CALL wasi:clocks/monotonic-clock#subscribe-instant");
+        let sleep_limit = unsafe { clocks.sleep_limit };
+        if mode() != MODE_FROZEN && sleep_limit != u64::MAX {
+            let duration = limited_duration(when.saturating_sub(instant_now()));
+            if duration > sleep_limit {
+                return limited_pollable(duration);
+            }
+        }
+        match mode() {
+            // time does not advance when frozen, so timers resolve immediately
+            // rather than blocking forever
+            MODE_FROZEN => Pollable::new(IoPollable::Null),
+            MODE_SIMULATED => Pollable::new(IoPollable::Timer(when)),
+            MODE_ADJUSTED => Pollable::new(IoPollable::Host(monotonic_clock::subscribe_instant(
+                to_host(when),
+            ))),
+            _ => Pollable::new(IoPollable::Host(monotonic_clock::subscribe_instant(when))),
+        }
+    }
+    fn subscribe_duration(when: u64) -> Pollable {
+        debug!("CALL wasi:clocks/monotonic-clock#subscribe-duration");
+        let duration = limited_duration(when);
+        if mode() != MODE_FROZEN && duration > unsafe { clocks.sleep_limit } {
+            return limited_pollable(duration);
+        }
+        duration_pollable(when)
+    }
+}
+
+impl WallClock for VirtAdapter {
+    fn now() -> Datetime {
+        debug!("CALL wasi:clocks/wall-clock#now");
+        match mode() {
+            MODE_FROZEN => Datetime {
+                seconds: unsafe { clocks.wall_seconds },
+                nanoseconds: unsafe { clocks.wall_nanoseconds },
+            },
+            // the simulated wall clock advances with the simulated monotonic clock
+            MODE_SIMULATED => {
+                let nanoseconds = unsafe { clocks.wall_nanoseconds as u64 + ELAPSED };
+                Datetime {
+                    seconds: unsafe { clocks.wall_seconds } + nanoseconds / 1_000_000_000,
+                    nanoseconds: (nanoseconds % 1_000_000_000) as u32,
+                }
+            }
+            MODE_ADJUSTED => {
+                let now = wall_clock::now();
+                Datetime {
+                    seconds: now
+                        .seconds
+                        .saturating_add_signed(unsafe { clocks.wall_offset }),
+                    nanoseconds: now.nanoseconds,
+                }
+            }
+            MODE_COARSENED => {
+                let now = wall_clock::now();
+                let nanoseconds =
+                    coarsen(now.seconds as u128 * 1_000_000_000 + now.nanoseconds as u128);
+                Datetime {
+                    seconds: (nanoseconds / 1_000_000_000) as u64,
+                    nanoseconds: (nanoseconds % 1_000_000_000) as u32,
+                }
+            }
+            _ => datetime_map(wall_clock::now()),
+        }
+    }
+    fn resolution() -> Datetime {
+        debug!("CALL wasi:clocks/wall-clock#resolution");
+        match mode() {
+            MODE_FROZEN | MODE_SIMULATED => Datetime {
+                seconds: 0,
+                nanoseconds: 1,
+            },
+            MODE_COARSENED => {
+                let granularity = unsafe { clocks.granularity };
+                Datetime {
+                    seconds: granularity / 1_000_000_000,
+                    nanoseconds: (granularity % 1_000_000_000) as u32,
+                }
+            }
+            _ => datetime_map(wall_clock::resolution()),
+        }
+    }
+}
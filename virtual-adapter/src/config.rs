@@ -1,6 +1,12 @@
-use crate::bindings::exports::wasi::config::store::{Error, Guest as Store};
-use crate::bindings::wasi::config::store;
-use crate::VirtAdapter;
+use crate::bindings::exports::wasi::cli::environment::Guest as Environment;
+use crate::bindings::exports::wasi::config0_2_0_draft::store::{Error, Guest as Store};
+use crate::bindings::exports::wasi::config0_2_0_rc_1::store::{
+    Error as ErrorRc1, Guest as StoreRc1,
+};
+use crate::bindings::wasi::config0_2_0_draft::store;
+use crate::bindings::wasi::config0_2_0_rc_1::store as store_rc1;
+use crate::io::{log, DEBUG};
+use crate::{check_required, debug, glob_match, redact, VirtAdapter};
 
 #[repr(C)]
 pub struct Config {
@@ -11,6 +17,13 @@ pub struct Config {
     /// on the fallback lookups
     /// [byte 1]
     host_fallback_allow: bool,
+    /// Whether to read host properties from the environment variables
+    /// [byte 2]
+    host_source_env: bool,
+    /// Key case transform for environment variable names
+    /// (0 = verbatim, 1 = lower, 2 = lower dotted)
+    /// [byte 3]
+    host_env_key_case: u8,
     /// How many host fields are defined in the data pointer
     /// [byte 4]
     host_field_cnt: u32,
@@ -22,17 +35,51 @@ pub struct Config {
     /// up to the lengths previously provided.
     /// [byte 12]
     host_field_data: *const u8,
+    /// How many configuration properties are unset
+    /// (these follow the allow or deny patterns in the data)
+    /// [byte 16]
+    unset_cnt: u32,
+    /// How many of the allow or deny entries are `*` patterns
+    /// (these follow the exact allow or deny keys in the data)
+    /// [byte 20]
+    host_allow_or_deny_pattern_cnt: u32,
+    /// Whether a host namespace prefix follows the unset keys
+    /// [byte 24]
+    host_prefix_cnt: u32,
+    /// The `wasi:config/store` version imported from the host
+    /// (0 = 0.2.0-draft, 1 = 0.2.0-rc.1)
+    /// [byte 28]
+    host_store_version: u8,
+    /// How many required keys and value patterns are defined
+    /// (these follow the prefix in the data)
+    /// [byte 32]
+    required_cnt: u32,
+    /// How many secret keys or patterns are defined
+    /// (these follow the required keys in the data)
+    /// [byte 36]
+    secret_cnt: u32,
 }
 
 #[no_mangle]
 pub static mut config: Config = Config {
     host_fallback: true,
     host_fallback_allow: false,
+    host_source_env: false,
+    host_env_key_case: 0,
     host_field_cnt: 0,
     host_allow_or_deny_cnt: 0,
     host_field_data: 0 as *const u8,
+    unset_cnt: 0,
+    host_allow_or_deny_pattern_cnt: 0,
+    host_prefix_cnt: 0,
+    host_store_version: 0,
+    required_cnt: 0,
+    secret_cnt: 0,
 };
 
+/// Whether the required host properties have been found
+static mut REQUIRED_CHECKED: bool = false;
+
 fn read_data_str(offset: &mut isize) -> &'static str {
     let data: *const u8 = unsafe { config.host_field_data.offset(*offset) };
     let byte_len = unsafe { (data as *const u32).read() } as usize;
@@ -47,32 +94,170 @@ fn read_data_str(offset: &mut isize) -> &'static str {
     unsafe { core::str::from_utf8_unchecked(str_data) }
 }
 
+/// The host fallback policy, which follows the overrides in the data
+struct HostPolicy {
+    allow_or_deny: Vec<&'static str>,
+    allow_or_deny_patterns: Vec<&'static str>,
+    unset: Vec<&'static str>,
+    prefix: Option<&'static str>,
+    required: Vec<(&'static str, &'static str)>,
+    secrets: Vec<&'static str>,
+}
+
+impl HostPolicy {
+    fn read(data_offset: &mut isize) -> Self {
+        let mut allow_or_deny = Vec::new();
+        for _ in 0..unsafe { config.host_allow_or_deny_cnt } {
+            let allow_or_deny_key = read_data_str(data_offset);
+            allow_or_deny.push(allow_or_deny_key);
+        }
+        let mut allow_or_deny_patterns = Vec::new();
+        for _ in 0..unsafe { config.host_allow_or_deny_pattern_cnt } {
+            allow_or_deny_patterns.push(read_data_str(data_offset));
+        }
+        let mut unset = Vec::new();
+        for _ in 0..unsafe { config.unset_cnt } {
+            unset.push(read_data_str(data_offset));
+        }
+        let prefix = if unsafe { config.host_prefix_cnt } > 0 {
+            Some(read_data_str(data_offset))
+        } else {
+            None
+        };
+        let mut required = Vec::new();
+        for _ in 0..unsafe { config.required_cnt } {
+            let key = read_data_str(data_offset);
+            let pattern = read_data_str(data_offset);
+            required.push((key, pattern));
+        }
+        let mut secrets = Vec::new();
+        for _ in 0..unsafe { config.secret_cnt } {
+            secrets.push(read_data_str(data_offset));
+        }
+        HostPolicy {
+            allow_or_deny,
+            allow_or_deny_patterns,
+            unset,
+            prefix,
+            required,
+            secrets,
+        }
+    }
+
+    fn is_unset(&self, key: &str) -> bool {
+        self.unset.binary_search(&key).is_ok()
+    }
+
+    /// Whether a guest key may fall back to the host
+    fn host_allowed(&self, key: &str) -> bool {
+        // fallback ASSUMES that all data is alphabetically ordered
+        if !unsafe { config.host_fallback } || self.is_unset(key) {
+            return false;
+        }
+        let is_allow_list = unsafe { config.host_fallback_allow };
+        let in_list = self.allow_or_deny.binary_search(&key).is_ok()
+            || self
+                .allow_or_deny_patterns
+                .iter()
+                .any(|pattern| glob_match(pattern, key));
+        is_allow_list && in_list || !is_allow_list && !in_list
+    }
+
+    /// The host key for a guest key
+    fn host_key(&self, key: &str) -> String {
+        match self.prefix {
+            Some(prefix) => format!("{prefix}{key}"),
+            None => key.to_string(),
+        }
+    }
+
+    /// The guest key for a host key, if it is within the namespace
+    fn guest_key(&self, key: String) -> Option<String> {
+        match self.prefix {
+            Some(prefix) => key.strip_prefix(prefix).map(|key| key.to_string()),
+            None => Some(key),
+        }
+    }
+
+    /// All host properties within the namespace, by guest key
+    fn host_properties(&self) -> Result<Vec<(String, String)>, Error> {
+        if !unsafe { config.host_source_env } {
+            return Ok(host_get_all()?
+                .into_iter()
+                .filter_map(|(key, value)| Some((self.guest_key(key)?, value)))
+                .collect());
+        }
+        // read through the virtualized environment
+        let key_case = unsafe { config.host_env_key_case };
+        Ok(<VirtAdapter as Environment>::get_environment()
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let key = self.guest_key(key)?;
+                let key = match key_case {
+                    0 => key,
+                    1 => key.to_lowercase(),
+                    _ => key.to_lowercase().replace('_', "."),
+                };
+                Some((key, value))
+            })
+            .collect())
+    }
+
+    /// The host value for a guest key, if permitted
+    fn host_value(&self, key: &str) -> Result<Option<String>, Error> {
+        if !self.host_allowed(key) {
+            return Ok(None);
+        }
+        if unsafe { config.host_source_env } {
+            return Ok(self
+                .host_properties()?
+                .into_iter()
+                .find(|(host_key, _)| *host_key == key)
+                .map(|(_, value)| value));
+        }
+        host_get(&self.host_key(key))
+    }
+
+    /// Check the required host properties, until they have all been found
+    fn check_required(&self) -> Result<(), Error> {
+        if unsafe { REQUIRED_CHECKED } {
+            return Ok(());
+        }
+        for (key, pattern) in &self.required {
+            let value = self.host_value(key)?;
+            check_required("config property", key, pattern, value.as_deref())
+                .map_err(Error::Upstream)?;
+        }
+        unsafe { REQUIRED_CHECKED = true };
+        Ok(())
+    }
+}
+
 impl Store for VirtAdapter {
     fn get(key: String) -> Result<Option<String>, Error> {
         let mut data_offset: isize = 0;
+        let mut override_val = None;
         for _ in 0..unsafe { config.host_field_cnt } {
             let config_key = read_data_str(&mut data_offset);
-            let config_val = read_data_str(&mut data_offset);
             if key == *config_key {
-                return Ok(Some(config_val.to_string()));
-            }
-        }
-
-        // fallback ASSUMES that all data is alphabetically ordered
-        if unsafe { config.host_fallback } {
-            let mut allow_or_deny = Vec::new();
-            for _ in 0..unsafe { config.host_allow_or_deny_cnt } {
-                let allow_or_deny_key = read_data_str(&mut data_offset);
-                allow_or_deny.push(allow_or_deny_key);
-            }
-
-            let is_allow_list = unsafe { config.host_fallback_allow };
-            let in_list = allow_or_deny.binary_search(&key.as_ref()).is_ok();
-            if is_allow_list && in_list || !is_allow_list && !in_list {
-                return store::get(&key).map_err(config_err_map);
+                override_val = Some(read_data_str(&mut data_offset).to_string());
+            } else {
+                read_data_str(&mut data_offset);
             }
         }
-        Ok(None)
+        let policy = HostPolicy::read(&mut data_offset);
+        policy.check_required()?;
+        let value = match override_val {
+            Some(config_val) => Some(config_val),
+            None => policy.host_value(&key)?,
+        };
+        debug!(
+            "CALL wasi:config/store#get KEY={key} VALUE={:?}",
+            value
+                .as_deref()
+                .map(|value| redact(&policy.secrets, &key, value))
+        );
+        Ok(value)
     }
 
     fn get_all() -> Result<Vec<(String, String)>, Error> {
@@ -84,35 +269,76 @@ impl Store for VirtAdapter {
             configuration.push((config_key.to_string(), config_val.to_string()));
         }
         let override_len = configuration.len();
-        // fallback ASSUMES that all data is alphabetically ordered
-        if unsafe { config.host_fallback } {
-            let mut allow_or_deny = Vec::new();
-            for _ in 0..unsafe { config.host_allow_or_deny_cnt } {
-                let allow_or_deny_key = read_data_str(&mut data_offset);
-                allow_or_deny.push(allow_or_deny_key);
-            }
+        let policy = HostPolicy::read(&mut data_offset);
+        policy.check_required()?;
 
-            let is_allow_list = unsafe { config.host_fallback_allow };
-            for (key, value) in store::get_all().map_err(config_err_map)? {
+        if unsafe { config.host_fallback } {
+            for (key, value) in policy.host_properties()? {
                 if configuration[0..override_len]
                     .binary_search_by_key(&&key, |(s, _)| s)
                     .is_ok()
                 {
                     continue;
                 }
-                let in_list = allow_or_deny.binary_search(&key.as_ref()).is_ok();
-                if is_allow_list && in_list || !is_allow_list && !in_list {
+                if policy.host_allowed(&key) {
                     configuration.push((key, value));
                 }
             }
+            // the host store order is unspecified, so sort the host properties
+            configuration[override_len..].sort();
+        }
+
+        debug!("CALL wasi:config/store#get-all");
+        for (key, value) in &configuration {
+            debug!("  {key}={}", redact(&policy.secrets, key, value));
         }
         Ok(configuration)
     }
 }
 
+/// The guest may import any supported store version, which are all served
+/// by the same implementation
+impl StoreRc1 for VirtAdapter {
+    fn get(key: String) -> Result<Option<String>, ErrorRc1> {
+        <VirtAdapter as Store>::get(key).map_err(rc1_err_map)
+    }
+
+    fn get_all() -> Result<Vec<(String, String)>, ErrorRc1> {
+        <VirtAdapter as Store>::get_all().map_err(rc1_err_map)
+    }
+}
+
+fn host_get(key: &str) -> Result<Option<String>, Error> {
+    match unsafe { config.host_store_version } {
+        0 => store::get(key).map_err(config_err_map),
+        _ => store_rc1::get(key).map_err(config_rc1_err_map),
+    }
+}
+
+fn host_get_all() -> Result<Vec<(String, String)>, Error> {
+    match unsafe { config.host_store_version } {
+        0 => store::get_all().map_err(config_err_map),
+        _ => store_rc1::get_all().map_err(config_rc1_err_map),
+    }
+}
+
 fn config_err_map(err: store::Error) -> Error {
     match err {
         store::Error::Upstream(msg) => Error::Upstream(msg),
         store::Error::Io(msg) => Error::Io(msg),
     }
 }
+
+fn config_rc1_err_map(err: store_rc1::Error) -> Error {
+    match err {
+        store_rc1::Error::Upstream(msg) => Error::Upstream(msg),
+        store_rc1::Error::Io(msg) => Error::Io(msg),
+    }
+}
+
+fn rc1_err_map(err: Error) -> ErrorRc1 {
+    match err {
+        Error::Upstream(msg) => ErrorRc1::Upstream(msg),
+        Error::Io(msg) => ErrorRc1::Io(msg),
+    }
+}
@@ -1,6 +1,7 @@
 use crate::bindings::exports::wasi::cli::environment::Guest as Environment;
 use crate::bindings::wasi::cli::environment;
-use crate::VirtAdapter;
+use crate::io::{fatal, log, DEBUG};
+use crate::{check_required, debug, glob_match, redact, VirtAdapter};
 
 #[repr(C)]
 pub struct Env {
@@ -11,6 +12,12 @@ pub struct Env {
     /// on the fallback lookups
     /// [byte 1]
     host_fallback_allow: bool,
+    /// Whether to pass through the host arguments
+    /// [byte 2]
+    host_args: bool,
+    /// Whether to pass through the host working directory
+    /// [byte 3]
+    host_cwd: bool,
     /// How many host fields are defined in the data pointer
     /// [byte 4]
     host_field_cnt: u32,
@@ -22,15 +29,60 @@ pub struct Env {
     /// up to the lengths previously provided.
     /// [byte 12]
     host_field_data: *const u8,
+    /// How many arguments to insert after the program name
+    /// (these follow the allow or deny keys in the data)
+    /// [byte 16]
+    args_prepend_cnt: u32,
+    /// How many arguments to add after the host arguments
+    /// (these follow the prepended arguments in the data)
+    /// [byte 20]
+    args_append_cnt: u32,
+    /// Whether a virtual working directory follows the arguments
+    /// [byte 24]
+    cwd_cnt: u32,
+    /// How many of the allow or deny entries are `*` patterns
+    /// (these follow the exact allow or deny keys in the data)
+    /// [byte 28]
+    host_allow_or_deny_pattern_cnt: u32,
+    /// How many host to guest key mappings are defined
+    /// (these follow the allow or deny patterns in the data)
+    /// [byte 32]
+    host_mapping_cnt: u32,
+    /// Whether to interpolate host variables into the override values
+    /// [byte 36]
+    interpolate: bool,
+    /// How many environment variables are unset
+    /// (these follow the mappings in the data)
+    /// [byte 40]
+    unset_cnt: u32,
+    /// How many required keys and value patterns are defined
+    /// (these follow the unset keys in the data)
+    /// [byte 44]
+    required_cnt: u32,
+    /// How many secret keys or patterns are defined
+    /// (these follow the required keys in the data)
+    /// [byte 48]
+    secret_cnt: u32,
 }
 
 #[no_mangle]
 pub static mut env: Env = Env {
     host_fallback: true,
     host_fallback_allow: false,
+    host_args: true,
+    host_cwd: true,
     host_field_cnt: 0,
     host_allow_or_deny_cnt: 0,
     host_field_data: 0 as *const u8,
+    args_prepend_cnt: 0,
+    args_append_cnt: 0,
+    cwd_cnt: 0,
+    host_allow_or_deny_pattern_cnt: 0,
+    host_mapping_cnt: 0,
+    interpolate: false,
+    unset_cnt: 0,
+    required_cnt: 0,
+    secret_cnt: 0,
 };
 
 fn read_data_str(offset: &mut isize) -> &'static str {
@@ -47,6 +99,48 @@ fn read_data_str(offset: &mut isize) -> &'static str {
     unsafe { core::str::from_utf8_unchecked(str_data) }
 }
 
+/// The data offset of the arguments, which follow the overrides,
+/// allow or deny lists, mappings, unset, required and secret keys
+fn args_data_offset() -> isize {
+    let mut data_offset: isize = 0;
+    for _ in 0..unsafe {
+        env.host_field_cnt * 2
+            + env.host_allow_or_deny_cnt
+            + env.host_allow_or_deny_pattern_cnt
+            + env.host_mapping_cnt * 2
+            + env.unset_cnt
+            + env.required_cnt * 2
+            + env.secret_cnt
+    } {
+        read_data_str(&mut data_offset);
+    }
+    data_offset
+}
+
+/// Resolve `${VAR}` references in a value, with `$$` escaping a literal `$`
+///
+/// The reference syntax is validated at virtualization time.
+fn interpolate<'a>(value: &str, lookup: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut interpolated = String::new();
+    let mut rest = value;
+    while let Some(idx) = rest.find('$') {
+        interpolated.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        if let Some(next) = rest.strip_prefix('$') {
+            interpolated.push('$');
+            rest = next;
+        } else if let Some(next) = rest.strip_prefix('{') {
+            let end = next.find('}').unwrap_or(next.len());
+            interpolated.push_str(lookup(&next[..end]).unwrap_or(""));
+            rest = next.get(end + 1..).unwrap_or("");
+        } else {
+            interpolated.push('$');
+        }
+    }
+    interpolated.push_str(rest);
+    interpolated
+}
+
 impl Environment for VirtAdapter {
     fn get_environment() -> Vec<(String, String)> {
         let mut environment = Vec::new();
@@ -57,34 +151,143 @@ impl Environment for VirtAdapter {
             environment.push((env_key.to_string(), env_val.to_string()));
         }
         let override_len = environment.len();
+        let mut allow_or_deny = Vec::new();
+        for _ in 0..unsafe { env.host_allow_or_deny_cnt } {
+            let allow_or_deny_key = read_data_str(&mut data_offset);
+            allow_or_deny.push(allow_or_deny_key);
+        }
+        let mut allow_or_deny_patterns = Vec::new();
+        for _ in 0..unsafe { env.host_allow_or_deny_pattern_cnt } {
+            allow_or_deny_patterns.push(read_data_str(&mut data_offset));
+        }
+        let mut mappings = Vec::new();
+        for _ in 0..unsafe { env.host_mapping_cnt } {
+            let host_key = read_data_str(&mut data_offset);
+            let guest_key = read_data_str(&mut data_offset);
+            mappings.push((host_key, guest_key));
+        }
+        let mut unset = Vec::new();
+        for _ in 0..unsafe { env.unset_cnt } {
+            unset.push(read_data_str(&mut data_offset));
+        }
+        let mut required = Vec::new();
+        for _ in 0..unsafe { env.required_cnt } {
+            let key = read_data_str(&mut data_offset);
+            let pattern = read_data_str(&mut data_offset);
+            required.push((key, pattern));
+        }
+        let mut secrets = Vec::new();
+        for _ in 0..unsafe { env.secret_cnt } {
+            secrets.push(read_data_str(&mut data_offset));
+        }
+
         // fallback ASSUMES that all data is alphabetically ordered
-        if unsafe { env.host_fallback } {
-            let mut allow_or_deny = Vec::new();
-            for _ in 0..unsafe { env.host_allow_or_deny_cnt } {
-                let allow_or_deny_key = read_data_str(&mut data_offset);
-                allow_or_deny.push(allow_or_deny_key);
+        let host_fallback = unsafe { env.host_fallback };
+        let is_allow_list = unsafe { env.host_fallback_allow };
+        let host_allowed = |key: &str| {
+            let in_list = allow_or_deny.binary_search(&key).is_ok()
+                || allow_or_deny_patterns
+                    .iter()
+                    .any(|pattern| glob_match(pattern, key));
+            host_fallback && (is_allow_list && in_list || !is_allow_list && !in_list)
+        };
+        let host_environment = if host_fallback || !mappings.is_empty() {
+            environment::get_environment()
+        } else {
+            Vec::new()
+        };
+
+        if unsafe { env.interpolate } {
+            for (_, value) in environment.iter_mut() {
+                *value = interpolate(value, |name| {
+                    if !host_allowed(name) || unset.binary_search(&name).is_ok() {
+                        return None;
+                    }
+                    host_environment
+                        .iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.as_ref())
+                });
             }
+        }
 
-            let is_allow_list = unsafe { env.host_fallback_allow };
-            for (key, value) in environment::get_environment() {
-                if environment[0..override_len]
-                    .binary_search_by_key(&&key, |(s, _)| s)
-                    .is_ok()
-                {
-                    continue;
-                }
-                let in_list = allow_or_deny.binary_search(&key.as_ref()).is_ok();
-                if is_allow_list && in_list || !is_allow_list && !in_list {
-                    environment.push((key, value));
+        for (key, value) in host_environment {
+            // mapped variables are only exposed under their guest key
+            let key = match mappings.binary_search_by_key(&key.as_str(), |(host_key, _)| *host_key)
+            {
+                Ok(idx) => mappings[idx].1.to_string(),
+                Err(_) => {
+                    if mappings.iter().any(|(_, guest_key)| *guest_key == key)
+                        || !host_allowed(&key)
+                    {
+                        continue;
+                    }
+                    key
                 }
+            };
+            if environment[0..override_len]
+                .binary_search_by_key(&&key, |(s, _)| s)
+                .is_ok()
+                || unset.binary_search(&key.as_ref()).is_ok()
+            {
+                continue;
             }
+            environment.push((key, value));
+        }
+
+        for (key, pattern) in required {
+            let value = environment
+                .iter()
+                .find(|(env_key, _)| env_key == key)
+                .map(|(_, value)| value.as_ref());
+            if let Err(msg) = check_required("env var", key, pattern, value) {
+                fatal(&msg);
+            }
+        }
+
+        debug!("CALL wasi:cli/environment#get-environment");
+        for (key, value) in &environment {
+            debug!("  {key}={}", redact(&secrets, key, value));
         }
         environment
     }
     fn get_arguments() -> Vec<String> {
-        environment::get_arguments()
+        let mut data_offset = args_data_offset();
+        let mut prepend = Vec::new();
+        for _ in 0..unsafe { env.args_prepend_cnt } {
+            prepend.push(read_data_str(&mut data_offset).to_string());
+        }
+        let mut append = Vec::new();
+        for _ in 0..unsafe { env.args_append_cnt } {
+            append.push(read_data_str(&mut data_offset).to_string());
+        }
+
+        let mut arguments = Vec::new();
+        if unsafe { env.host_args } {
+            // the program name stays first
+            let mut host_arguments = environment::get_arguments().into_iter();
+            arguments.extend(host_arguments.next());
+            arguments.extend(prepend);
+            arguments.extend(host_arguments);
+        } else {
+            arguments.extend(prepend);
+        }
+        arguments.extend(append);
+        arguments
     }
     fn initial_cwd() -> Option<String> {
-        environment::initial_cwd()
+        // the working directory follows the arguments
+        if unsafe { env.cwd_cnt } > 0 {
+            let mut data_offset = args_data_offset();
+            for _ in 0..unsafe { env.args_prepend_cnt + env.args_append_cnt } {
+                read_data_str(&mut data_offset);
+            }
+            return Some(read_data_str(&mut data_offset).to_string());
+        }
+        if unsafe { env.host_cwd } {
+            environment::initial_cwd()
+        } else {
+            None
+        }
     }
 }
@@ -0,0 +1,53 @@
+use crate::bindings::exports::local::virt::exit_status::Guest as ExitStatus;
+use crate::bindings::exports::wasi::cli::exit::Guest as CliExit;
+use crate::bindings::wasi::cli::exit as host_exit;
+use crate::io::{fatal, log, DEBUG};
+use crate::{debug, VirtAdapter};
+
+/// Exit the host instance
+const MODE_HOST: u8 = 0;
+/// Trap with a message including the status
+const MODE_TRAP: u8 = 1;
+/// Return to the guest, recording the status for the exit-status accessor
+const MODE_RECORD: u8 = 2;
+
+#[repr(C)]
+pub struct Exit {
+    /// The exit mode
+    /// [byte 0]
+    mode: u8,
+    /// The reported exit codes for guest success and failure
+    /// [byte 1]
+    codes: [u8; 2],
+}
+
+#[no_mangle]
+pub static mut exit: Exit = Exit {
+    mode: MODE_HOST,
+    codes: [0, 1],
+};
+
+/// The status the guest exited with in the record mode
+static mut STATUS: Option<u8> = None;
+
+impl CliExit for VirtAdapter {
+    fn exit(status: Result<(), ()>) {
+        debug!("CALL wasi:cli/exit#exit STATUS={status:?}");
+        let code = unsafe { exit.codes[if status.is_ok() { 0 } else { 1 }] };
+        match unsafe { exit.mode } {
+            MODE_TRAP => fatal(&format!("wasi:cli/exit called with status {code}")),
+            MODE_RECORD => unsafe {
+                STATUS = Some(code);
+            },
+            // the host exit only distinguishes success from failure
+            _ => host_exit::exit(if code == 0 { Ok(()) } else { Err(()) }),
+        }
+    }
+}
+
+impl ExitStatus for VirtAdapter {
+    fn exit_status() -> Option<u8> {
+        debug!("CALL local:virt/exit-status#exit-status");
+        unsafe { STATUS }
+    }
+}
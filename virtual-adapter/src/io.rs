@@ -1,7 +1,9 @@
 #![allow(static_mut_refs)]
 
 use crate::bindings;
+use crate::clocks;
 
+use bindings::exports::local::virt::captured_output::Guest as CapturedOutput;
 use bindings::exports::wasi::cli::stderr::Guest as Stderr;
 use bindings::exports::wasi::cli::stdin::Guest as Stdin;
 use bindings::exports::wasi::cli::stdout::Guest as Stdout;
@@ -10,7 +12,7 @@ use bindings::exports::wasi::cli::terminal_output::{GuestTerminalOutput, Termina
 use bindings::exports::wasi::cli::terminal_stderr::Guest as TerminalStderr;
 use bindings::exports::wasi::cli::terminal_stdin::Guest as TerminalStdin;
 use bindings::exports::wasi::cli::terminal_stdout::Guest as TerminalStdout;
-use bindings::exports::wasi::clocks::monotonic_clock::Guest as MonotonicClock;
+use bindings::exports::wasi::clocks::wall_clock::Datetime;
 use bindings::exports::wasi::filesystem::preopens::Guest as Preopens;
 use bindings::exports::wasi::filesystem::types::{
     Advice, Descriptor, DescriptorBorrow, DescriptorFlags, DescriptorStat, DescriptorType,
@@ -55,7 +57,7 @@ use crate::bindings::wasi::filesystem::types as filesystem_types;
 use crate::bindings::wasi::io::streams;
 
 // these are all the subsystems which touch streams + poll
-use crate::bindings::wasi::clocks::monotonic_clock;
+use crate::bindings::wasi::clocks::wall_clock;
 use crate::bindings::wasi::http::outgoing_handler;
 use crate::bindings::wasi::http::types as http_types;
 use crate::bindings::wasi::io::poll;
@@ -66,7 +68,7 @@ use crate::bindings::wasi::sockets::udp;
 use crate::VirtAdapter;
 
 // for debugging build
-const DEBUG: bool = cfg!(feature = "debug");
+pub(crate) const DEBUG: bool = cfg!(feature = "debug");
 
 use std::alloc::Layout;
 use std::cell::Cell;
@@ -85,6 +87,12 @@ const FLAGS_IGNORE_STDOUT: u32 = 1 << 4;
 const FLAGS_IGNORE_STDERR: u32 = 1 << 5;
 const FLAGS_HOST_PREOPENS: u32 = 1 << 6;
 const FLAGS_HOST_PASSTHROUGH: u32 = 1 << 7;
+const FLAGS_NO_TERMINAL_STDIN: u32 = 1 << 8;
+const FLAGS_NO_TERMINAL_STDOUT: u32 = 1 << 9;
+const FLAGS_NO_TERMINAL_STDERR: u32 = 1 << 10;
+const FLAGS_CAPTURE_STDOUT: u32 = 1 << 11;
+const FLAGS_CAPTURE_STDERR: u32 = 1 << 12;
+const FLAGS_HOST_STDERR: u32 = 1 << 13;
 
 #[macro_export]
 macro_rules! debug {
@@ -100,7 +108,11 @@ macro_rules! debug {
     };
 }
 
-fn log(msg: &str) {
+pub(crate) fn log(msg: &str) {
+    // the host stderr import is stubbed unless stdio is virtualized
+    if (unsafe { io.flags }) & FLAGS_HOST_STDERR == 0 {
+        return;
+    }
     if unsafe { &STATE.host_stderr }.is_none() {
         unsafe { STATE.host_stderr = Some(stderr::get_stderr()) };
     }
@@ -112,6 +124,66 @@ fn log(msg: &str) {
         .blocking_write_and_flush(msg.as_bytes());
 }
 
+/// Report a fatal virtualization error on the host stderr before trapping
+///
+/// The error is only reported when stdio is virtualized.
+pub(crate) fn fatal(msg: &str) -> ! {
+    log(msg);
+    std::process::abort()
+}
+
+/// A file under the host preopens which is written from the start and then
+/// appended to, keeping the descriptor open between appends
+pub(crate) struct HostAppendFile {
+    file: filesystem_types::Descriptor,
+    offset: u64,
+}
+
+impl HostAppendFile {
+    /// Open a file under the host preopens for appending, creating the file when
+    /// missing and truncating it otherwise
+    ///
+    /// The path is resolved against the longest matching host preopen.
+    pub(crate) fn open(path: &str) -> Result<Self, filesystem_types::ErrorCode> {
+        let mut preopen = None;
+        for (fd, name) in preopens::get_directories() {
+            let name = name.trim_end_matches('/');
+            let Some(rel_path) = path
+                .strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('/'))
+            else {
+                continue;
+            };
+            if preopen.as_ref().map_or(true, |(_, preopen_name_len, _)| {
+                name.len() > *preopen_name_len
+            }) {
+                preopen = Some((fd, name.len(), rel_path.to_string()));
+            }
+        }
+        let Some((fd, _, rel_path)) = preopen else {
+            return Err(filesystem_types::ErrorCode::NoEntry);
+        };
+        let file = fd.open_at(
+            filesystem_types::PathFlags::empty(),
+            &rel_path,
+            filesystem_types::OpenFlags::CREATE | filesystem_types::OpenFlags::TRUNCATE,
+            filesystem_types::DescriptorFlags::WRITE,
+        )?;
+        Ok(HostAppendFile { file, offset: 0 })
+    }
+
+    /// Append bytes to the end of the file
+    pub(crate) fn append(&mut self, bytes: &[u8]) -> Result<(), filesystem_types::ErrorCode> {
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let written = self.file.write(bytes, self.offset)?;
+            self.offset += written;
+            bytes = &bytes[written as usize..];
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum IoError {
     FsCode(ErrorCode),
@@ -133,13 +205,34 @@ pub enum IoInputStream {
 pub enum IoOutputStream {
     Null,
     Err,
+    Capture {
+        stream: CapturedStream,
+        tee: Option<streams::OutputStream>,
+    },
     Host(streams::OutputStream),
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum CapturedStream {
+    Stdout,
+    Stderr,
+}
+
+impl CapturedStream {
+    fn buffer(&self) -> &'static mut Vec<u8> {
+        match self {
+            CapturedStream::Stdout => unsafe { &mut STATE.captured_stdout },
+            CapturedStream::Stderr => unsafe { &mut STATE.captured_stderr },
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum IoPollable {
     Null,
     Host(poll::Pollable),
+    /// A simulated monotonic clock timer for the given instant
+    Timer(u64),
 }
 
 // static fs config
@@ -156,6 +249,8 @@ enum AllowCfg {
     Allow,
     Deny,
     Ignore,
+    Capture,
+    Tee,
 }
 
 impl Io {
@@ -180,7 +275,13 @@ impl Io {
         }
     }
     fn stdout() -> AllowCfg {
-        if (unsafe { io.flags }) & FLAGS_ENABLE_STDOUT > 0 {
+        if (unsafe { io.flags }) & FLAGS_CAPTURE_STDOUT > 0 {
+            if (unsafe { io.flags }) & FLAGS_ENABLE_STDOUT > 0 {
+                AllowCfg::Tee
+            } else {
+                AllowCfg::Capture
+            }
+        } else if (unsafe { io.flags }) & FLAGS_ENABLE_STDOUT > 0 {
             AllowCfg::Allow
         } else if (unsafe { io.flags }) & FLAGS_IGNORE_STDOUT > 0 {
             AllowCfg::Ignore
@@ -189,7 +290,13 @@ impl Io {
         }
     }
     fn stderr() -> AllowCfg {
-        if (unsafe { io.flags }) & FLAGS_ENABLE_STDERR > 0 {
+        if (unsafe { io.flags }) & FLAGS_CAPTURE_STDERR > 0 {
+            if (unsafe { io.flags }) & FLAGS_ENABLE_STDERR > 0 {
+                AllowCfg::Tee
+            } else {
+                AllowCfg::Capture
+            }
+        } else if (unsafe { io.flags }) & FLAGS_ENABLE_STDERR > 0 {
             AllowCfg::Allow
         } else if (unsafe { io.flags }) & FLAGS_IGNORE_STDERR > 0 {
             AllowCfg::Ignore
@@ -197,6 +304,15 @@ impl Io {
             AllowCfg::Deny
         }
     }
+    fn terminal_stdin() -> bool {
+        (unsafe { io.flags }) & FLAGS_NO_TERMINAL_STDIN == 0
+    }
+    fn terminal_stdout() -> bool {
+        (unsafe { io.flags }) & FLAGS_NO_TERMINAL_STDOUT == 0
+    }
+    fn terminal_stderr() -> bool {
+        (unsafe { io.flags }) & FLAGS_NO_TERMINAL_STDERR == 0
+    }
     fn host_passthrough() -> bool {
         (unsafe { io.flags }) & FLAGS_HOST_PASSTHROUGH > 0
     }
@@ -447,6 +563,8 @@ pub struct IoState {
     preopen_directories: Vec<(FilesystemDescriptor, String)>,
     host_preopen_directories: BTreeMap<String, Rc<filesystem_types::Descriptor>>,
     host_stderr: Option<streams::OutputStream>,
+    captured_stdout: Vec<u8>,
+    captured_stderr: Vec<u8>,
 }
 
 impl IoState {
@@ -531,6 +649,8 @@ static mut STATE: IoState = IoState {
     preopen_directories: Vec::new(),
     host_preopen_directories: BTreeMap::new(),
     host_stderr: None,
+    captured_stdout: Vec::new(),
+    captured_stderr: Vec::new(),
 };
 
 impl Stdin for VirtAdapter {
@@ -539,7 +659,8 @@ impl Stdin for VirtAdapter {
         InputStream::new(match Io::stdin() {
             AllowCfg::Allow => IoInputStream::Host(stdin::get_stdin()),
             AllowCfg::Ignore => IoInputStream::Null,
-            AllowCfg::Deny => IoInputStream::Err,
+            // stdin cannot be captured
+            AllowCfg::Deny | AllowCfg::Capture | AllowCfg::Tee => IoInputStream::Err,
         })
     }
 }
@@ -551,6 +672,14 @@ impl Stdout for VirtAdapter {
             AllowCfg::Allow => IoOutputStream::Host(stdout::get_stdout()),
             AllowCfg::Ignore => IoOutputStream::Null,
             AllowCfg::Deny => IoOutputStream::Err,
+            AllowCfg::Capture => IoOutputStream::Capture {
+                stream: CapturedStream::Stdout,
+                tee: None,
+            },
+            AllowCfg::Tee => IoOutputStream::Capture {
+                stream: CapturedStream::Stdout,
+                tee: Some(stdout::get_stdout()),
+            },
         })
     }
 }
@@ -562,6 +691,14 @@ impl Stderr for VirtAdapter {
             AllowCfg::Allow => IoOutputStream::Host(stderr::get_stderr()),
             AllowCfg::Ignore => IoOutputStream::Null,
             AllowCfg::Deny => IoOutputStream::Err,
+            AllowCfg::Capture => IoOutputStream::Capture {
+                stream: CapturedStream::Stderr,
+                tee: None,
+            },
+            AllowCfg::Tee => IoOutputStream::Capture {
+                stream: CapturedStream::Stderr,
+                tee: Some(stderr::get_stderr()),
+            },
         })
     }
 }
@@ -569,6 +706,9 @@ impl Stderr for VirtAdapter {
 impl TerminalStdin for VirtAdapter {
     fn get_terminal_stdin() -> Option<TerminalInput> {
         debug!("CALL wasi:cli/terminal-stdin#get-terminal-stdin");
+        if !Io::terminal_stdin() {
+            return None;
+        }
         terminal_stdin::get_terminal_stdin()
             .map(|terminal_input| TerminalInput::new(CliTerminalInput(terminal_input)))
     }
@@ -577,6 +717,9 @@ impl TerminalStdin for VirtAdapter {
 impl TerminalStdout for VirtAdapter {
     fn get_terminal_stdout() -> Option<TerminalOutput> {
         debug!("CALL wasi:cli/terminal-stdout#get-terminal-stdout");
+        if !Io::terminal_stdout() {
+            return None;
+        }
         terminal_stdout::get_terminal_stdout()
             .map(|terminal_output| TerminalOutput::new(CliTerminalOutput(terminal_output)))
     }
@@ -585,35 +728,28 @@ impl TerminalStdout for VirtAdapter {
 impl TerminalStderr for VirtAdapter {
     fn get_terminal_stderr() -> Option<TerminalOutput> {
         debug!("CALL wasi:cli/terminal-stderr#get-terminal-stderr");
+        if !Io::terminal_stderr() {
+            return None;
+        }
         terminal_stderr::get_terminal_stderr()
             .map(|terminal_output| TerminalOutput::new(CliTerminalOutput(terminal_output)))
     }
 }
 
-impl GuestTerminalInput for CliTerminalInput {}
-impl GuestTerminalOutput for CliTerminalOutput {}
-
-impl MonotonicClock for VirtAdapter {
-    fn now() -> u64 {
-        debug!("CALL wasi:clocks/monotonic-clock#now");
-        monotonic_clock::now()
-    }
-    fn resolution() -> u64 {
-        debug!("CALL wasi:clocks/monotonic-clock#resolution");
-        monotonic_clock::resolution()
+impl CapturedOutput for VirtAdapter {
+    fn take_stdout() -> Vec<u8> {
+        debug!("CALL local:virt/captured-output#take-stdout");
+        std::mem::take(CapturedStream::Stdout.buffer())
     }
-    fn subscribe_instant(when: u64) -> Pollable {
-        debug!("CALL wasi:clocks/monotonic-clock#subscribe-instant");
-        let host_pollable = monotonic_clock::subscribe_instant(when);
-        Pollable::new(IoPollable::Host(host_pollable))
-    }
-    fn subscribe_duration(when: u64) -> Pollable {
-        debug!("CALL wasi:clocks/monotonic-clock#subscribe-duration");
-        let host_pollable = monotonic_clock::subscribe_duration(when);
-        Pollable::new(IoPollable::Host(host_pollable))
+    fn take_stderr() -> Vec<u8> {
+        debug!("CALL local:virt/captured-output#take-stderr");
+        std::mem::take(CapturedStream::Stderr.buffer())
     }
 }
 
+impl GuestTerminalInput for CliTerminalInput {}
+impl GuestTerminalOutput for CliTerminalOutput {}
+
 impl FilesystemTypes for VirtAdapter {
     type Descriptor = FilesystemDescriptor;
     type DirectoryEntryStream = FilesystemDirectoryEntryStream;
@@ -712,6 +848,7 @@ impl GuestPollable for IoPollable {
         match self {
             IoPollable::Host(pid) => pid.ready(),
             IoPollable::Null => true,
+            IoPollable::Timer(when) => clocks::timer_ready(*when),
         }
     }
 
@@ -720,6 +857,7 @@ impl GuestPollable for IoPollable {
         match self {
             IoPollable::Host(pid) => pid.block(),
             IoPollable::Null => (),
+            IoPollable::Timer(when) => clocks::advance_to(*when),
         }
     }
 }
@@ -729,6 +867,38 @@ impl Poll for VirtAdapter {
 
     fn poll(list: Vec<PollableBorrow<'_>>) -> Vec<u32> {
         debug!("CALL wasi:io/poll#poll-list PIDS={list:?}",);
+        // simulated timers only jump the clocks forward when nothing else is ready
+        if let Some(next_timer) = list
+            .iter()
+            .filter_map(|pid| match pid.get() {
+                IoPollable::Timer(when) => Some(*when),
+                _ => None,
+            })
+            .min()
+        {
+            let mut ready = list
+                .iter()
+                .map(|pid| match pid.get() {
+                    IoPollable::Host(host_pid) => host_pid.ready(),
+                    IoPollable::Timer(when) => clocks::timer_ready(*when),
+                    IoPollable::Null => true,
+                })
+                .collect::<Vec<_>>();
+            if !ready.contains(&true) {
+                clocks::advance_to(next_timer);
+                for (index, pid) in list.iter().enumerate() {
+                    if let IoPollable::Timer(when) = pid.get() {
+                        ready[index] = clocks::timer_ready(*when);
+                    }
+                }
+            }
+            return ready
+                .into_iter()
+                .enumerate()
+                .filter(|(_, ready)| *ready)
+                .map(|(index, _)| index.try_into().unwrap())
+                .collect();
+        }
         let has_host_polls = list
             .iter()
             .any(|pid| matches!(pid.get(), IoPollable::Host(_)));
@@ -1148,8 +1318,9 @@ impl GuestOutputStream for IoOutputStream {
     fn check_write(&self) -> Result<u64, StreamError> {
         debug!("CALL wasi:io/streams#output-stream.check_write SID={self:?}");
         match self {
-            Self::Null => Ok(1024 * 1024),
+            Self::Null | Self::Capture { tee: None, .. } => Ok(1024 * 1024),
             Self::Err => Err(StreamError::Closed),
+            Self::Capture { tee: Some(sid), .. } => sid.check_write().map_err(stream_err_map),
             Self::Host(sid) => sid.check_write().map_err(stream_err_map),
         }
     }
@@ -1158,6 +1329,13 @@ impl GuestOutputStream for IoOutputStream {
         match self {
             Self::Null => Ok(()),
             Self::Err => Err(StreamError::Closed),
+            Self::Capture { stream, tee } => {
+                stream.buffer().extend_from_slice(&bytes);
+                match tee {
+                    Some(sid) => sid.write(&bytes).map_err(stream_err_map),
+                    None => Ok(()),
+                }
+            }
             Self::Host(sid) => sid.write(&bytes).map_err(stream_err_map),
         }
     }
@@ -1166,22 +1344,31 @@ impl GuestOutputStream for IoOutputStream {
         match self {
             Self::Null => Ok(()),
             Self::Err => Err(StreamError::Closed),
+            Self::Capture { stream, tee } => {
+                stream.buffer().extend_from_slice(&bytes);
+                match tee {
+                    Some(sid) => sid.blocking_write_and_flush(&bytes).map_err(stream_err_map),
+                    None => Ok(()),
+                }
+            }
             Self::Host(sid) => sid.blocking_write_and_flush(&bytes).map_err(stream_err_map),
         }
     }
     fn flush(&self) -> Result<(), StreamError> {
         debug!("CALL wasi:io/streams#output-stream.flush SID={self:?}");
         match self {
-            Self::Null => Ok(()),
+            Self::Null | Self::Capture { tee: None, .. } => Ok(()),
             Self::Err => Err(StreamError::Closed),
+            Self::Capture { tee: Some(sid), .. } => sid.flush().map_err(stream_err_map),
             Self::Host(sid) => sid.flush().map_err(stream_err_map),
         }
     }
     fn blocking_flush(&self) -> Result<(), StreamError> {
         debug!("CALL wasi:io/streams#output-stream.blocking-flush SID={self:?}");
         match self {
-            Self::Null => Ok(()),
+            Self::Null | Self::Capture { tee: None, .. } => Ok(()),
             Self::Err => Err(StreamError::Closed),
+            Self::Capture { tee: Some(sid), .. } => sid.blocking_flush().map_err(stream_err_map),
             Self::Host(sid) => sid.blocking_flush().map_err(stream_err_map),
         }
     }
@@ -1190,6 +1377,14 @@ impl GuestOutputStream for IoOutputStream {
         match self {
             Self::Null => Ok(()),
             Self::Err => Err(StreamError::Closed),
+            Self::Capture { stream, tee } => {
+                let buffer = stream.buffer();
+                buffer.resize(buffer.len() + len as usize, 0);
+                match tee {
+                    Some(sid) => sid.write_zeroes(len).map_err(stream_err_map),
+                    None => Ok(()),
+                }
+            }
             Self::Host(sid) => sid.write_zeroes(len).map_err(stream_err_map),
         }
     }
@@ -1198,6 +1393,16 @@ impl GuestOutputStream for IoOutputStream {
         match self {
             Self::Null => Ok(()),
             Self::Err => Err(StreamError::Closed),
+            Self::Capture { stream, tee } => {
+                let buffer = stream.buffer();
+                buffer.resize(buffer.len() + len as usize, 0);
+                match tee {
+                    Some(sid) => sid
+                        .blocking_write_zeroes_and_flush(len)
+                        .map_err(stream_err_map),
+                    None => Ok(()),
+                }
+            }
             Self::Host(sid) => sid
                 .blocking_write_zeroes_and_flush(len)
                 .map_err(stream_err_map),
@@ -1212,6 +1417,12 @@ impl GuestOutputStream for IoOutputStream {
             Self::Err => {
                 return Err(StreamError::Closed);
             }
+            Self::Capture { .. } => {
+                let bytes = from.get::<IoInputStream>().read(len)?;
+                let read_len = bytes.len() as u64;
+                self.write(bytes)?;
+                return Ok(read_len);
+            }
             Self::Host(sid) => sid,
         };
         let from_sid = match from.get() {
@@ -1237,6 +1448,12 @@ impl GuestOutputStream for IoOutputStream {
             Self::Err => {
                 return Err(StreamError::Closed);
             }
+            Self::Capture { .. } => {
+                let bytes = from.get::<IoInputStream>().blocking_read(len)?;
+                let read_len = bytes.len() as u64;
+                self.blocking_write_and_flush(bytes)?;
+                return Ok(read_len);
+            }
             Self::Host(sid) => sid,
         };
         let from_sid = match from.get() {
@@ -1256,7 +1473,8 @@ impl GuestOutputStream for IoOutputStream {
     fn subscribe(&self) -> Pollable {
         debug!("CALL wasi:io/streams#output-stream.subscribe SID={self:?}");
         Pollable::new(match self {
-            Self::Null | Self::Err => IoPollable::Null,
+            Self::Null | Self::Err | Self::Capture { tee: None, .. } => IoPollable::Null,
+            Self::Capture { tee: Some(sid), .. } => IoPollable::Host(sid.subscribe()),
             Self::Host(descriptor) => IoPollable::Host(descriptor.subscribe()),
         })
     }
@@ -1891,9 +2109,16 @@ fn stat_map(s: filesystem_types::DescriptorStat) -> DescriptorStat {
         type_: descriptor_ty_map(s.type_),
         link_count: s.link_count,
         size: s.size,
-        data_access_timestamp: s.data_modification_timestamp,
-        data_modification_timestamp: s.data_modification_timestamp,
-        status_change_timestamp: s.status_change_timestamp,
+        data_access_timestamp: s.data_modification_timestamp.map(datetime_map),
+        data_modification_timestamp: s.data_modification_timestamp.map(datetime_map),
+        status_change_timestamp: s.status_change_timestamp.map(datetime_map),
+    }
+}
+
+pub(crate) fn datetime_map(d: wall_clock::Datetime) -> Datetime {
+    Datetime {
+        seconds: d.seconds,
+        nanoseconds: d.nanoseconds,
     }
 }
 
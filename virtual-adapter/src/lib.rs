@@ -1,11 +1,91 @@
 #![no_main]
 
+mod clocks;
 mod config;
 mod env;
+mod exit;
 mod io;
+mod random;
 
 pub(crate) struct VirtAdapter;
 
+/// Match a key against a pattern where `*` matches any sequence of characters
+pub(crate) fn glob_match(pattern: &str, key: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // the first part is anchored to the start
+    let Some(mut rest) = parts.next().and_then(|first| key.strip_prefix(first)) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // the last part is anchored to the end
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    // no wildcard, so the key must match exactly
+    rest.is_empty()
+}
+
+/// The value to display in debug output, redacting the values of secret keys
+pub(crate) fn redact<'a>(secrets: &[&str], key: &str, value: &'a str) -> &'a str {
+    if secrets.iter().any(|pattern| glob_match(pattern, key)) {
+        "<redacted>"
+    } else {
+        value
+    }
+}
+
+/// Check a required key against its value, where an empty pattern allows any value
+pub(crate) fn check_required(
+    kind: &str,
+    key: &str,
+    pattern: &str,
+    value: Option<&str>,
+) -> Result<(), String> {
+    let Some(value) = value else {
+        return Err(format!("required {kind} [{key}] is missing"));
+    };
+    if pattern.is_empty() {
+        return Ok(());
+    }
+    let matches = unsafe {
+        virt_required_match(pattern.as_ptr(), pattern.len(), value.as_ptr(), value.len())
+    };
+    if !matches {
+        return Err(format!(
+            "required {kind} [{key}] does not match the pattern [{pattern}]"
+        ));
+    }
+    Ok(())
+}
+
+/// Match a value as a whole against a required regex
+///
+/// This is exported for the virtualization to stub when no required patterns
+/// are checked at runtime, such that the regex engine is removed from the
+/// adapter, and so must not be inlined into its callers.
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C" fn virt_required_match(
+    pattern_ptr: *const u8,
+    pattern_len: usize,
+    value_ptr: *const u8,
+    value_len: usize,
+) -> bool {
+    let pattern = unsafe {
+        std::str::from_utf8_unchecked(std::slice::from_raw_parts(pattern_ptr, pattern_len))
+    };
+    let value =
+        unsafe { std::str::from_utf8_unchecked(std::slice::from_raw_parts(value_ptr, value_len)) };
+    // the pattern is validated at virtualization time
+    regex_lite::Regex::new(&format!("^(?:{pattern})$")).is_ok_and(|regex| regex.is_match(value))
+}
+
 pub(crate) mod bindings {
     #[cfg(all(feature = "wasi-0_2_1", not(feature = "wasi-0_2_3")))]
     wit_bindgen::generate!({
@@ -0,0 +1,241 @@
+use crate::bindings::exports::wasi::config0_2_0_draft::store::Guest as Store;
+use crate::bindings::exports::wasi::random::insecure::Guest as Insecure;
+use crate::bindings::exports::wasi::random::insecure_seed::Guest as InsecureSeed;
+use crate::bindings::exports::wasi::random::random::Guest as SecureRandom;
+use crate::bindings::wasi::random::{insecure, insecure_seed, random};
+use crate::io::{fatal, log, HostAppendFile, DEBUG};
+use crate::{debug, VirtAdapter};
+
+/// Read the host random source
+const MODE_HOST: u8 = 0;
+/// Generate the random data from the seeded generator
+const MODE_SEEDED: u8 = 1;
+/// Read the host random source, recording the random data to the log file
+const MODE_RECORD: u8 = 2;
+/// Replay the random data from the embedded log
+const MODE_REPLAY: u8 = 3;
+
+#[repr(C)]
+pub struct Random {
+    /// The `wasi:random/random` mode
+    /// [byte 0]
+    secure_mode: u8,
+    /// The `wasi:random/insecure` mode
+    /// [byte 1]
+    insecure_mode: u8,
+    /// The `wasi:random/insecure-seed` mode
+    /// [byte 2]
+    insecure_seed_mode: u8,
+    /// The seed of the generator, when not read from the config
+    /// [byte 8]
+    seed: u64,
+    /// The byte length of the config key to read the seed from at runtime,
+    /// with zero using the seed above
+    /// [byte 16]
+    seed_key_len: u32,
+    /// The config key bytes
+    /// [byte 20]
+    seed_key: *const u8,
+    /// The byte length of the path of the log file to record to, under the host preopens
+    /// [byte 24]
+    record_path_len: u32,
+    /// The record path bytes
+    /// [byte 28]
+    record_path: *const u8,
+    /// The byte length of the embedded log to replay
+    /// [byte 32]
+    replay_len: u32,
+    /// The embedded log bytes
+    /// [byte 36]
+    replay_data: *const u8,
+}
+
+#[no_mangle]
+pub static mut random: Random = Random {
+    secure_mode: MODE_HOST,
+    insecure_mode: MODE_HOST,
+    insecure_seed_mode: MODE_HOST,
+    seed: 0,
+    seed_key_len: 0,
+    seed_key: 0 as *const u8,
+    record_path_len: 0,
+    record_path: 0 as *const u8,
+    replay_len: 0,
+    replay_data: 0 as *const u8,
+};
+
+/// The generator state, shared by the seeded interfaces
+static mut STATE: u64 = 0;
+
+/// Whether the generator state has been seeded, which happens on first use
+static mut SEEDED: bool = false;
+
+/// The seed of the generator, reading it from the config when configured
+fn seed() -> u64 {
+    let seed_key_len = unsafe { random.seed_key_len };
+    if seed_key_len == 0 {
+        return unsafe { random.seed };
+    }
+    let key = unsafe {
+        core::str::from_utf8_unchecked(std::slice::from_raw_parts(
+            random.seed_key,
+            seed_key_len as usize,
+        ))
+    };
+    match <VirtAdapter as Store>::get(key.to_string()) {
+        Ok(Some(value)) => match value.trim().parse() {
+            Ok(seed) => seed,
+            Err(_) => fatal(&format!(
+                "random seed config property [{key}] value [{value}] is not a valid u64"
+            )),
+        },
+        Ok(None) => fatal(&format!("random seed config property [{key}] is missing")),
+        Err(_) => fatal(&format!(
+            "unable to read random seed config property [{key}]"
+        )),
+    }
+}
+
+/// The next value of the seeded generator
+fn next_u64() -> u64 {
+    let mut z = unsafe {
+        if !SEEDED {
+            STATE = seed();
+            SEEDED = true;
+        }
+        // SplitMix64
+        STATE = STATE.wrapping_add(0x9e3779b97f4a7c15);
+        STATE
+    };
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// The bytes of the embedded log replayed so far
+static mut REPLAY_OFFSET: usize = 0;
+
+/// The log file recorded to, opened on first use
+static mut RECORD_LOG: Option<HostAppendFile> = None;
+
+/// Record the random data returned from the host to the log file
+fn record(bytes: &[u8]) {
+    let path = unsafe {
+        core::str::from_utf8_unchecked(std::slice::from_raw_parts(
+            random.record_path,
+            random.record_path_len as usize,
+        ))
+    };
+    let log = unsafe { &mut *&raw mut RECORD_LOG };
+    if log.is_none() {
+        match HostAppendFile::open(path) {
+            Ok(file) => *log = Some(file),
+            Err(err) => fatal(&format!("unable to open random log [{path}]: {err:?}")),
+        }
+    }
+    if let Err(err) = log.as_mut().unwrap().append(bytes) {
+        fatal(&format!(
+            "unable to record random data to [{path}]: {err:?}"
+        ));
+    }
+}
+
+/// The next bytes of the embedded log, trapping when it is exhausted
+fn replay(len: u64) -> Vec<u8> {
+    let replay_len = unsafe { random.replay_len } as usize;
+    let offset = unsafe { REPLAY_OFFSET };
+    // the guest length is checked before casting, as it may exceed the address space
+    if len > (replay_len - offset) as u64 {
+        fatal(&format!(
+            "random replay log exhausted, reading {len} bytes at byte {offset} of {replay_len}"
+        ));
+    }
+    let len = len as usize;
+    unsafe {
+        REPLAY_OFFSET += len;
+        std::slice::from_raw_parts(random.replay_data.add(offset), len).to_vec()
+    }
+}
+
+fn replay_u64() -> u64 {
+    u64::from_le_bytes(replay(8).try_into().unwrap())
+}
+
+/// Record a host random value to the log file
+fn record_u64(value: u64) -> u64 {
+    record(&value.to_le_bytes());
+    value
+}
+
+/// Record host random bytes to the log file
+fn record_bytes(bytes: Vec<u8>) -> Vec<u8> {
+    record(&bytes);
+    bytes
+}
+
+fn next_bytes(len: u64) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(len as usize);
+    while (bytes.len() as u64) < len {
+        let remaining = len as usize - bytes.len();
+        let value = next_u64().to_le_bytes();
+        bytes.extend_from_slice(&value[..remaining.min(8)]);
+    }
+    bytes
+}
+
+impl SecureRandom for VirtAdapter {
+    fn get_random_bytes(len: u64) -> Vec<u8> {
+        debug!("CALL wasi:random/random#get-random-bytes LEN={len}");
+        match unsafe { random.secure_mode } {
+            MODE_SEEDED => next_bytes(len),
+            MODE_RECORD => record_bytes(random::get_random_bytes(len)),
+            MODE_REPLAY => replay(len),
+            _ => random::get_random_bytes(len),
+        }
+    }
+    fn get_random_u64() -> u64 {
+        debug!("CALL wasi:random/random#get-random-u64");
+        match unsafe { random.secure_mode } {
+            MODE_SEEDED => next_u64(),
+            MODE_RECORD => record_u64(random::get_random_u64()),
+            MODE_REPLAY => replay_u64(),
+            _ => random::get_random_u64(),
+        }
+    }
+}
+
+impl Insecure for VirtAdapter {
+    fn get_insecure_random_bytes(len: u64) -> Vec<u8> {
+        debug!("CALL wasi:random/insecure#get-insecure-random-bytes LEN={len}");
+        match unsafe { random.insecure_mode } {
+            MODE_SEEDED => next_bytes(len),
+            MODE_RECORD => record_bytes(insecure::get_insecure_random_bytes(len)),
+            MODE_REPLAY => replay(len),
+            _ => insecure::get_insecure_random_bytes(len),
+        }
+    }
+    fn get_insecure_random_u64() -> u64 {
+        debug!("CALL wasi:random/insecure#get-insecure-random-u64");
+        match unsafe { random.insecure_mode } {
+            MODE_SEEDED => next_u64(),
+            MODE_RECORD => record_u64(insecure::get_insecure_random_u64()),
+            MODE_REPLAY => replay_u64(),
+            _ => insecure::get_insecure_random_u64(),
+        }
+    }
+}
+
+impl InsecureSeed for VirtAdapter {
+    fn insecure_seed() -> (u64, u64) {
+        debug!("CALL wasi:random/insecure-seed#insecure-seed");
+        match unsafe { random.insecure_seed_mode } {
+            MODE_SEEDED => (next_u64(), next_u64()),
+            MODE_RECORD => {
+                let (first, second) = insecure_seed::insecure_seed();
+                (record_u64(first), record_u64(second))
+            }
+            MODE_REPLAY => (replay_u64(), replay_u64()),
+            _ => insecure_seed::insecure_seed(),
+        }
+    }
+}